@@ -1,70 +1,87 @@
-use crate::fatal;
-use crate::Params;
+use crate::cancel::Cancel;
+use crate::config::Config;
+use crate::error::{Error, Result};
 
 use rand::Rng;
 use regex::Regex;
 use reqwest::{self, header};
 use std::collections::HashMap;
-use std::error::Error;
 use std::fs::*;
-use std::io::{Read, Write};
+use std::io::Write;
 use std::path::Path;
 
+// A downloaded picture, saved as <dir>/<resolution>/<filename>.
 #[derive(Debug)]
-struct Pic {
-    filename: String,
-    body: Vec<u8>,
+pub struct Pic {
+    pub filename: String,
+    pub body: Vec<u8>,
 }
 
 impl Pic {
-    fn new(filename: String, body: Vec<u8>) -> Pic {
+    pub fn new(filename: String, body: Vec<u8>) -> Pic {
         Pic {
-            filename: filename,
-            body: body,
+            filename,
+            body,
         }
     }
 }
 
-pub fn download(params: &Params) {
-    let resolution = params.resolution.clone().expect("Resolutions are wrong");
-    let empty_dir = params.download_empty;
-    let sfw = params.download_sfw;
+// What a download saved, and the pictures that couldn't be fetched without stopping the others.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub saved: usize,
+    pub failed: Vec<Error>,
+}
+
+// Fetches pictures for every resolution from the sources of the config.
+// With `empty_dir` the wallpaper directory is emptied first. Once `cancel` is set no more
+// pictures are fetched, the ones fetched so far are still saved.
+pub fn download(config: &Config, empty_dir: bool, cancel: &Cancel) -> Result<Report> {
+    let sources = config.sources.iter().filter_map(|s| source(s)).collect();
+    download_from(config, empty_dir, sources, cancel)
+}
 
-    let dir = Path::new(&params.dir);
+// Like download, with sources of your own. One of them is picked at random for every resolution,
+// the next one is tried when it has nothing for it or can't be reached.
+pub fn download_from(
+    config: &Config,
+    empty_dir: bool,
+    sources: Vec<Box<dyn Wallpaper>>,
+    cancel: &Cancel,
+) -> Result<Report> {
+    let resolution = config.resolutions();
+    let sfw = config.download_sfw;
+
+    let dir = Path::new(&config.dir);
     if empty_dir {
         //del previous files or dirs
         for entry in dir
             .read_dir()
-            .expect(&format!("Directory {} can't read", params.dir))
+            .map_err(|e| Error::io(&config.dir, e))?
+            .flatten()
         {
-            if let Ok(entry) = entry {
-                match entry.file_type() {
-                    Ok(t) => {
-                        if t.is_dir() {
-                            if let Err(e) = remove_dir_all(entry.path()) {
-                                if e.kind() != std::io::ErrorKind::Other
-                                    && e.kind() != std::io::ErrorKind::NotFound
-                                {
-                                    fatal!("Directory {} is failed remove:{:?}", &params.dir, e);
-                                }
+            match entry.file_type() {
+                Ok(t) => {
+                    if t.is_dir() {
+                        if let Err(e) = remove_dir_all(entry.path()) {
+                            if e.kind() != std::io::ErrorKind::Other
+                                && e.kind() != std::io::ErrorKind::NotFound
+                            {
+                                Err(Error::io(entry.path().display(), e))?
                             }
-                        } else {
-                            if let Err(e) = remove_file(entry.path()) {
-                                if e.kind() != std::io::ErrorKind::Other
-                                    && e.kind() != std::io::ErrorKind::NotFound
-                                {
-                                    fatal!(
-                                        "File {} is failed remove:{:?}",
-                                        entry.path().to_string_lossy(),
-                                        e
-                                    );
-                                }
+                        }
+                    } else {
+                        if let Err(e) = remove_file(entry.path()) {
+                            if e.kind() != std::io::ErrorKind::Other
+                                && e.kind() != std::io::ErrorKind::NotFound
+                            {
+                                Err(Error::io(entry.path().display(), e))?
                             }
                         }
                     }
-                    Err(e) => {
-                        fatal!("Get file types error:{:?}", e);
-                    }
+                }
+                Err(e) => {
+                    Err(Error::io(entry.path().display(), e))?
                 }
             }
         }
@@ -72,183 +89,141 @@ pub fn download(params: &Params) {
 
     //create picture dirs
     for i in resolution.iter() {
-        let pic_dir = format!("{}{}", params.dir, i);
+        let pic_dir = format!("{}{}", config.dir, i);
         match create_dir(&pic_dir) {
             Ok(()) => {}
             Err(e) => match e.kind() {
                 std::io::ErrorKind::AlreadyExists => {}
                 _ => {
-                    fatal!("Couldn't create dir {}:{:?}", pic_dir, e);
+                    Err(Error::io(&pic_dir, e))?
                 }
             },
         }
     }
 
     let mut pics: HashMap<String, Vec<Pic>> = HashMap::new();
-    let mut pic_count: usize = 0;
+    let mut report = Report::default();
     //get pictures
-    println!("Starting...");
     for i in resolution.iter() {
-        let mut available_wallpapers: Vec<Box<dyn Wallpaper>> = vec![Box::new(Wallhaven)];
-        
-        while available_wallpapers.len() > 0 {
+        if cancel.is_cancelled() {
+            break;
+        }
+        let mut available_wallpapers = sources.iter().collect::<Vec<&Box<dyn Wallpaper>>>();
+
+        while !available_wallpapers.is_empty() {
             let index = rand::thread_rng().gen_range(0, available_wallpapers.len());
             match available_wallpapers
                 .get(index)
                 .unwrap()
-                .get_pics(i, sfw, &params.proxy)
+                .get_pics(i, sfw, &config.proxy, cancel)
             {
-                Ok(ret) => {
-                    pic_count += ret.len();
-                    pics.insert(i.to_owned(), ret);
+                Ok(ret) if ret.iter().any(|p| p.is_ok()) => {
+                    let mut fetched = vec!();
+                    for p in ret {
+                        match p {
+                            Ok(pic) => fetched.push(pic),
+                            Err(e) => report.failed.push(e),
+                        }
+                    }
+                    report.saved += fetched.len();
+                    pics.insert(i.to_owned(), fetched);
 
                     break;
                 },
-                Err(e) => {
-                    match e {
-                        DownloadError::NotFound => {
-                            available_wallpapers.remove(index);
-
-                            if available_wallpapers.len() == 0 {
-                                fatal!("Main error Not Found");
-                            }
-                        },
-                        _ => panic!("Main error {}", e),
-                    }
-                    
+                Ok(ret) => {
+                    report.failed.extend(ret.into_iter().filter_map(|p| p.err()));
                 },
+                Err(e) => report.failed.push(e),
+            }
+
+            available_wallpapers.remove(index);
+            if available_wallpapers.is_empty() {
+                Err(Error::source(
+                    &config.sources.join(","),
+                    format!("no wallpapers found for {}", i),
+                ))?
             }
         }
     }
-    println!("Gets {} wallpapers", pic_count);
 
     for (r, p) in pics.iter() {
-        let pic_dir = format!("{}{}", params.dir, r);
-        save_pics(p, &pic_dir);
+        let pic_dir = format!("{}{}", config.dir, r);
+        save_pics(p, &pic_dir)?;
     }
+
+    Ok(report)
 }
 
-fn save_pics(pics: &Vec<Pic>, pic_dir: &str) {
+fn save_pics(pics: &[Pic], pic_dir: &str) -> Result<()> {
     for pic in pics.iter() {
         let file_name = format!("{}/{}", pic_dir, pic.filename);
         let path = Path::new(&file_name);
 
         let mut file = match File::create(path) {
             Err(why) => {
-                fatal!("Couldn't create {}: {}", file_name, why.to_string());
+                Err(Error::io(&file_name, why))?
             }
             Ok(file) => file,
         };
 
-        match file.write_all(&pic.body) {
-            Err(why) => {
-                fatal!("Couldn't write to {}:{}", file_name, why.to_string());
-            }
-            Ok(_) => {}
-        }
-    }
-}
-
-static mut SINGLETON_HTTP_CLIENT: Option<Singleton> = None;
-
-struct Singleton {
-    v: reqwest::blocking::Client,
-}
-
-impl Singleton {
-    fn new(proxy: &Option<String>) -> &Singleton {
-        unsafe {
-            match &SINGLETON_HTTP_CLIENT {
-                Some(r) => r,
-                None => {
-                    let mut headers = header::HeaderMap::new();
-                    headers.insert("User-agent", header::HeaderValue::from_static(
-                            "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/95.0.4638.69 Safari/537.36"));
-                    let mut client = reqwest::blocking::Client::builder().default_headers(headers);
-                    if let Some(p) = proxy {
-                        client = client.proxy(reqwest::Proxy::https(p).unwrap());
-                    }
-                    let client = client.build().unwrap();
-                    SINGLETON_HTTP_CLIENT = Some(Singleton { v: client });
-                    Singleton::new(proxy)
-                }
-            }
+        if let Err(why) = file.write_all(&pic.body) {
+            Err(Error::io(&file_name, why))?
         }
     }
-}
 
-#[derive(Debug)]
-enum DownloadError {
-    Reqwest(reqwest::Error),
-    Io(std::io::Error),
-    Regex(regex::Error),
-    NotFound,
+    Ok(())
 }
 
-impl std::fmt::Display for DownloadError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            DownloadError::Reqwest(error) => {
-                write!(f, "{}", error)
-            },
-            DownloadError::Io(error) => {
-                write!(f, "{}", error)
-            },
-            DownloadError::Regex(error) => {
-                write!(f, "{}", error)
-            },
-            DownloadError::NotFound => {
-                write!(f, "Not found")
-            },
-        }
+// Built for every call, so a proxy changed by a reload is picked up.
+fn client(proxy: &Option<String>) -> Result<reqwest::blocking::Client> {
+    let mut headers = header::HeaderMap::new();
+    headers.insert("User-agent", header::HeaderValue::from_static(
+            "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/95.0.4638.69 Safari/537.36"));
+    let mut client = reqwest::blocking::Client::builder().default_headers(headers);
+    if let Some(p) = proxy {
+        let proxy = reqwest::Proxy::https(p)
+            .map_err(|e| Error::Config(format!("proxy {}:{}", p, e)))?;
+        client = client.proxy(proxy);
     }
-}
-
-impl From<reqwest::Error> for DownloadError {
-    fn from(err: reqwest::Error) -> Self {
-        DownloadError::Reqwest(err)
-    }
-}
-
-impl From<std::io::Error> for DownloadError {
-    fn from(err: std::io::Error) -> Self {
-        DownloadError::Io(err)
-    }
-}
 
-impl From<regex::Error> for DownloadError {
-    fn from(err: regex::Error) -> Self {
-        DownloadError::Regex(err)
-    }
+    client
+        .build()
+        .map_err(|e| Error::Config(format!("http client:{}", e)))
 }
 
-impl std::error::Error for DownloadError {}
-
-struct Wallhaven;
-struct Wstock;
+pub struct Wallhaven;
+pub struct Wstock;
 
-trait Wallpaper {
+// A site pictures are downloaded from. No pictures or an error moves on to the next source, a picture
+// that can't be fetched is returned as its error. Fetching should stop once `cancel` is set.
+pub trait Wallpaper {
     fn get_pics(
         &self,
         resolution: &str,
         sfw: bool,
         proxy: &Option<String>,
-    ) -> Result<Vec<Pic>, DownloadError>;
+        cancel: &Cancel,
+    ) -> Result<Vec<Result<Pic>>>;
+}
+
+// The sources known by name in the config, see config::SOURCES.
+pub fn source(name: &str) -> Option<Box<dyn Wallpaper>> {
+    match name {
+        "wallhaven" => Some(Box::new(Wallhaven)),
+        "wallpaperstock" => Some(Box::new(Wstock)),
+        _ => None,
+    }
 }
 
 impl Wallhaven {
     fn get_pic_from_detail_page_url(
         &self,
+        client: &reqwest::blocking::Client,
         url: &str,
-        proxy: &Option<String>,
-    ) -> Result<Pic, Box<dyn Error>> {
-        let client = &Singleton::new(proxy).v;
-        let mut res = client.get(url).send()?;
-        let mut body = "".to_string();
+    ) -> Result<Pic> {
+        let body = get_text(client, url)?;
 
-        res.read_to_string(&mut body)?;
-
-        let re = Regex::new("id=\"wallpaper\" src=\"(.*?)\"")?;
+        let re = Regex::new("id=\"wallpaper\" src=\"(.*?)\"").map_err(|e| Error::source("wallhaven", e))?;
         let mut full_pic_url = "".to_string();
         for caps in re.captures_iter(&body) {
             full_pic_url = Self::parse_pic_url(caps[1].to_string());
@@ -256,9 +231,7 @@ impl Wallhaven {
 
         let filename = get_basename(&full_pic_url);
 
-        let mut res = client.get(&full_pic_url).send()?;
-        let mut body = Vec::new();
-        res.read_to_end(&mut body)?;
+        let body = get_bytes(client, &full_pic_url)?;
 
         Ok(Pic::new(filename, body))
     }
@@ -274,15 +247,11 @@ impl Wallhaven {
 impl Wstock {
     fn get_pic_from_detail_page_url(
         &self,
+        client: &reqwest::blocking::Client,
         url: &str,
-        proxy: &Option<String>,
-    ) -> Result<Pic, Box<dyn Error>> {
-        let client = &Singleton::new(proxy).v;
-
+    ) -> Result<Pic> {
         let filename = get_basename(url);
-        let mut res = client.get(url).send()?;
-        let mut body = Vec::new();
-        res.read_to_end(&mut body)?;
+        let body = get_bytes(client, url)?;
 
         Ok(Pic::new(filename, body))
     }
@@ -294,7 +263,8 @@ impl Wallpaper for Wallhaven {
         resolution: &str,
         sfw: bool,
         proxy: &Option<String>,
-    ) -> Result<Vec<Pic>, DownloadError> {
+        cancel: &Cancel,
+    ) -> Result<Vec<Result<Pic>>> {
         let mut category = 111;
         let mut purity = 110;
         if sfw {
@@ -302,7 +272,7 @@ impl Wallpaper for Wallhaven {
             purity = 100;
         }
 
-        let client = &Singleton::new(proxy).v;
+        let client = &client(proxy)?;
         let url = format!(
             "{}{}{}{}{}{}{}",
             "https://wallhaven.cc/search?categories=",
@@ -313,22 +283,15 @@ impl Wallpaper for Wallhaven {
             resolution,
             "&sorting=random&order=desc"
         );
-        let mut res = client.get(&url).send().expect("an error");
-        let mut body = "".to_string();
+        let body = get_text(client, &url)?;
 
-        res.read_to_string(&mut body)?;
-
-        let re = Regex::new("class=\"preview\"\\s+href=\"(.*?)\"")?;
+        let re = Regex::new("class=\"preview\"\\s+href=\"(.*?)\"").map_err(|e| Error::source("wallhaven", e))?;
         let mut pics = Vec::new();
         for caps in re.captures_iter(&body) {
-            match self.get_pic_from_detail_page_url(&caps[1], proxy) {
-                Ok(pic) => {
-                    pics.push(pic);
-                }
-                Err(e) => {
-                    println!("{}", e);
-                }
+            if cancel.is_cancelled() {
+                break;
             }
+            pics.push(self.get_pic_from_detail_page_url(client, &caps[1]));
         }
 
         Ok(pics)
@@ -341,52 +304,174 @@ impl Wallpaper for Wstock {
         resolution: &str,
         _sfw: bool,
         proxy: &Option<String>,
-    ) -> Result<Vec<Pic>, DownloadError> {
-        let client = &Singleton::new(proxy).v;
+        cancel: &Cancel,
+    ) -> Result<Vec<Result<Pic>>> {
+        let client = &client(proxy)?;
         let prefix_url = "https://wallpaperstock.net";
         let url = format!("{}/wallpapers_{}r.html", prefix_url, resolution,);
-        let mut res = client.get(&url).send().expect("an error");
-        let mut body = "".to_string();
-        res.read_to_string(&mut body)?;
-        let re = Regex::new("class=\"pagination\">.*?a>\\.\\.\\.<a.*?>(\\d+)</")?;
+        let body = get_text(client, &url)?;
+        let re = Regex::new("class=\"pagination\">.*?a>\\.\\.\\.<a.*?>(\\d+)</")
+            .map_err(|e| Error::source("wallpaperstock", e))?;
         let matches = &re.captures_iter(&body).next();
         if matches.is_none() {
-            return Err(DownloadError::NotFound);
+            return Ok(vec!());
         }
 
-        let max_page_match = (matches.as_ref().unwrap()[1]).parse::<u32>().unwrap();
+        let max_page_match = (matches.as_ref().unwrap()[1])
+            .parse::<u32>()
+            .map_err(|e| Error::source("wallpaperstock", format!("page count:{}", e)))?;
 
-        let random_page = rand::thread_rng().gen_range(1, max_page_match);
+        let random_page = rand::thread_rng().gen_range(1, max_page_match.max(1) + 1);
         let url = format!(
             "{}/wallpapers_p{}_{}r.html",
             prefix_url, random_page, resolution,
         );
-        let mut res = client.get(&url).send().expect("an error");
-        let mut body = "".to_string();
-        res.read_to_string(&mut body)?;
+        let body = get_text(client, &url)?;
 
-        let re = Regex::new("class=\"links\">[\\s\\S]*?href='(.*?)'")?;
+        let re = Regex::new("class=\"links\">[\\s\\S]*?href='(.*?)'").map_err(|e| Error::source("wallpaperstock", e))?;
 
         let mut pics = Vec::new();
         for caps in re.captures_iter(&body) {
+            if cancel.is_cancelled() {
+                break;
+            }
             let new_path = &caps[1]
                 .replace("-wallpapers_w", "_wallpapers_")
                 .replace(".html", &format!("_{}{}", resolution, ".jpg"));
-            match self.get_pic_from_detail_page_url(&format!("{}{}", prefix_url, new_path), proxy) {
-                Ok(pic) => {
-                    pics.push(pic);
-                }
-                Err(e) => {
-                    println!("{}", e);
-                }
-            }
+            pics.push(self.get_pic_from_detail_page_url(client, &format!("{}{}", prefix_url, new_path)));
         }
 
         Ok(pics)
     }
 }
 
+fn get_text(client: &reqwest::blocking::Client, url: &str) -> Result<String> {
+    client
+        .get(url)
+        .send()
+        .and_then(|res| res.text())
+        .map_err(|e| Error::network(url, e))
+}
+
+fn get_bytes(client: &reqwest::blocking::Client, url: &str) -> Result<Vec<u8>> {
+    client
+        .get(url)
+        .send()
+        .and_then(|res| res.bytes())
+        .map(|body| body.to_vec())
+        .map_err(|e| Error::network(url, e))
+}
+
 fn get_basename(url: &str) -> String {
     let pieces = url.split("/");
     pieces.last().unwrap().to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::gen_rand_string;
+
+    struct Fixed;
+
+    impl Wallpaper for Fixed {
+        fn get_pics(
+            &self,
+            resolution: &str,
+            _sfw: bool,
+            _proxy: &Option<String>,
+            _cancel: &Cancel,
+        ) -> Result<Vec<Result<Pic>>> {
+            Ok(vec!(
+                Ok(Pic::new(format!("{}.jpg", resolution), vec!(1, 2, 3))),
+                Err(Error::source("fixed", "b.jpg: timed out")),
+            ))
+        }
+    }
+
+    struct Empty;
+
+    impl Wallpaper for Empty {
+        fn get_pics(
+            &self,
+            _resolution: &str,
+            _sfw: bool,
+            _proxy: &Option<String>,
+            _cancel: &Cancel,
+        ) -> Result<Vec<Result<Pic>>> {
+            Ok(vec!())
+        }
+    }
+
+    struct Unreachable;
+
+    impl Wallpaper for Unreachable {
+        fn get_pics(
+            &self,
+            _resolution: &str,
+            _sfw: bool,
+            _proxy: &Option<String>,
+            _cancel: &Cancel,
+        ) -> Result<Vec<Result<Pic>>> {
+            Err(Error::source("unreachable", "connection refused"))
+        }
+    }
+
+    fn config(dir: &Path) -> Config {
+        Config::builder()
+            .dir(&dir.display().to_string())
+            .resolution(vec!("1920x1080".to_string()))
+            .monitors(vec!())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn a_source_with_nothing_moves_on_to_the_next() {
+        let dir = std::env::temp_dir().join(format!("wallpaper-test-{}", gen_rand_string()));
+        let config = config(&dir);
+
+        let report = download_from(&config, false, vec!(Box::new(Empty), Box::new(Fixed)), &Cancel::new()).unwrap();
+        assert_eq!(report.saved, 1);
+        assert!(dir.join("1920x1080/1920x1080.jpg").exists());
+
+        match download_from(&config, false, vec!(Box::new(Empty)), &Cancel::new()) {
+            Err(Error::Source { .. }) => {}
+            other => panic!("expected a source error, got {:?}", other),
+        }
+        let _ = remove_dir_all(dir);
+    }
+
+    #[test]
+    fn an_unreachable_source_is_reported_and_skipped() {
+        let dir = std::env::temp_dir().join(format!("wallpaper-test-{}", gen_rand_string()));
+        let config = config(&dir);
+
+        let report = download_from(&config, false, vec!(Box::new(Unreachable), Box::new(Fixed)), &Cancel::new()).unwrap();
+        assert_eq!(report.saved, 1);
+        assert!(report.failed.iter().all(|e| matches!(e, Error::Source { .. })));
+
+        match download_from(&config, false, vec!(Box::new(Unreachable)), &Cancel::new()) {
+            Err(Error::Source { .. }) => {}
+            other => panic!("expected a source error, got {:?}", other),
+        }
+        let _ = remove_dir_all(dir);
+    }
+
+    #[test]
+    fn a_cancelled_download_leaves_the_next_one_alone() {
+        let dir = std::env::temp_dir().join(format!("wallpaper-test-{}", gen_rand_string()));
+        let config = config(&dir);
+        let saved = dir.join("1920x1080/1920x1080.jpg");
+
+        let cancel = Cancel::new();
+        cancel.cancel();
+        download_from(&config, false, vec!(Box::new(Fixed)), &cancel).unwrap();
+        assert!(!saved.exists());
+
+        let report = download_from(&config, false, vec!(Box::new(Fixed)), &Cancel::new()).unwrap();
+        assert_eq!((report.saved, report.failed.len()), (1, 1));
+        assert_eq!(read(&saved).unwrap(), vec!(1, 2, 3));
+        let _ = remove_dir_all(dir);
+    }
+}
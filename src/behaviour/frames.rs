@@ -0,0 +1,159 @@
+use crate::cache;
+use crate::cancel::Cancel;
+use crate::config::Config;
+use crate::dependency::IMAGEMAGICK;
+use crate::error::{Error, Result};
+use crate::function::ScaleMode;
+use crate::log::Log;
+use crate::runner::CommandRunner;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+// The frames of the video or gif in playing order. They are split into jpgs once and taken
+// from the cache in `cache_dir` afterwards, see cache::key. Setting `cancel` kills ffmpeg or convert.
+pub fn extract(
+    config: &Config,
+    cache_dir: &Path,
+    runner: &dyn CommandRunner,
+    log: &Log,
+    cancel: &Cancel,
+) -> Result<Vec<String>> {
+    let file = config.file.clone().unwrap_or_default();
+    let resolution = target_resolution(config);
+    // A gif keeps all its frames, the fps only sets how fast they are shown.
+    let fps = if config.is_video { config.fps } else { 0 };
+    let key = cache::key(&file, resolution.as_deref(), fps)?;
+    if let Some(frames) = cache::lookup(cache_dir, &key) {
+        return Ok(frames);
+    }
+
+    let source = fs::canonicalize(&file).map(|p| p.display().to_string()).unwrap_or_else(|_e| file.clone());
+    cache::store(cache_dir, &key, &source, |dir| {
+        let frames = format!("{}/filename%09d.jpg", dir.display());
+        if config.is_video {
+            log.message("Start processing video files...");
+            let mut filters = vec!();
+            if fps > 0 {
+                filters.push(format!("fps={}", fps));
+            }
+            if let Some(r) = &resolution {
+                filters.push(format!("scale={}:force_original_aspect_ratio=increase", r.replace('x', ":")));
+            }
+            let filters = filters.join(",");
+            let mut args = vec!("-y", "-ss", "00:00", "-i", &file);
+            if !filters.is_empty() {
+                args.append(&mut vec!("-vf", &filters));
+            }
+            args.push(&frames);
+            runner.run_cancellable("ffmpeg", &args, cancel)?;
+        } else {
+            log.message("Start processing gif files...");
+            let resize = resolution.as_ref().map(|r| format!("{}^", r));
+            let mut args = vec!(&file[..], "-coalesce");
+            if let Some(r) = &resize {
+                args.append(&mut vec!("-resize", r));
+            }
+            args.push(&frames);
+            runner.run_cancellable(imagemagick(), &args, cancel)?;
+        }
+        log.message("Ok");
+
+        Ok(())
+    })
+}
+
+// Frames are scaled down (or up) to just cover every monitor when the backend scales them anyway,
+// center, tile and max show them at their own size.
+fn target_resolution(config: &Config) -> Option<String> {
+    match config.mode {
+        ScaleMode::Fill | ScaleMode::Fit | ScaleMode::Stretch => {}
+        _ => return None,
+    }
+    let sizes = config
+        .resolutions()
+        .iter()
+        .filter_map(|r| r.split_once('x'))
+        .filter_map(|(w, h)| Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?)))
+        .collect::<Vec<(u32, u32)>>();
+    let width = sizes.iter().map(|s| s.0).max()?;
+    let height = sizes.iter().map(|s| s.1).max()?;
+
+    Some(format!("{}x{}", width, height))
+}
+
+// magick with ImageMagick 7, convert with 6.
+pub fn imagemagick() -> &'static str {
+    IMAGEMAGICK.program().unwrap_or("convert")
+}
+
+// How long every frame is shown at speed 1: a set fps wins, gifs have a delay for every frame
+// and videos a frame rate.
+pub fn durations(config: &Config, runner: &dyn CommandRunner, log: &Log) -> Vec<Duration> {
+    let file = config.file.clone().unwrap_or_default();
+    if config.fps > 0 {
+        return vec!(Duration::from_secs_f64(1.0 / config.fps as f64));
+    }
+    if config.is_gif {
+        return gif_delays(&file).unwrap_or_else(|e| {
+            log.message(format!("{}, playing at 10 fps", e));
+            vec!(GIF_DEFAULT_DELAY)
+        });
+    }
+
+    match video_fps(&file, runner) {
+        Ok(fps) => vec!(Duration::from_secs_f64(1.0 / fps)),
+        Err(e) => {
+            log.message(format!("{}, playing at 25 fps", e));
+            vec!(Duration::from_secs_f64(1.0 / 25.0))
+        }
+    }
+}
+
+// Browsers show frames without a delay (or 10ms) for 100ms, gifs are made to look right there.
+const GIF_DEFAULT_DELAY: Duration = Duration::from_millis(100);
+
+// One delay for every frame, in the order convert -coalesce writes them.
+pub fn gif_delays(file: &str) -> Result<Vec<Duration>> {
+    let reader = fs::File::open(file).map_err(|e| Error::io(file, e))?;
+    let invalid = |e: gif::DecodingError| Error::io(file, io::Error::new(io::ErrorKind::InvalidData, e));
+    let mut decoder = gif::DecodeOptions::new().read_info(reader).map_err(invalid)?;
+
+    let mut delays = vec!();
+    while let Some(frame) = decoder.next_frame_info().map_err(invalid)? {
+        delays.push(match frame.delay {
+            0 | 1 => GIF_DEFAULT_DELAY,
+            d => Duration::from_millis(d as u64 * 10),
+        });
+    }
+
+    Ok(delays)
+}
+
+// ffprobe prints the average frame rate as a fraction, 30000/1001.
+pub fn video_fps(file: &str, runner: &dyn CommandRunner) -> Result<f64> {
+    let output = runner.run(
+        "ffprobe",
+        &[
+            "-v",
+            "error",
+            "-select_streams",
+            "v:0",
+            "-show_entries",
+            "stream=avg_frame_rate",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+            file,
+        ],
+    )?;
+
+    parse_frame_rate(output.trim()).ok_or_else(|| Error::dependency("ffprobe", format!("no frame rate for {}", file)))
+}
+
+fn parse_frame_rate(rate: &str) -> Option<f64> {
+    let (num, den) = rate.split_once('/').unwrap_or((rate, "1"));
+    let fps = num.parse::<f64>().ok()? / den.parse::<f64>().ok()?;
+
+    Some(fps).filter(|f| f.is_finite() && *f > 0.0)
+}
@@ -1 +1,4 @@
-pub mod download;
\ No newline at end of file
+pub mod download;
+pub mod frames;
+pub mod player;
+pub mod rotation;
\ No newline at end of file
@@ -0,0 +1,230 @@
+use std::time::{Duration, Instant};
+
+// The shortest a frame is shown, GIF delays are counted in hundredths of a second.
+const MIN_FRAME: Duration = Duration::from_millis(10);
+
+// Plays frames for their own durations. Every frame is due when the previous one's time is up,
+// counted from when that one was due rather than from when it got on screen, so a slow backend
+// doesn't make the animation drift: frames that are over already are dropped instead.
+pub struct Player {
+    frames: Vec<String>,
+    // How long every frame stays at speed 1.
+    durations: Vec<Duration>,
+    speed: f64,
+    // 0 plays forever.
+    loop_count: u32,
+    // The next frame to show and how many times all of them were shown.
+    frame: usize,
+    loops: u32,
+    due: Instant,
+    dropped: u64,
+}
+
+impl Player {
+    // Frames without a duration of their own get the last one.
+    pub fn new(frames: Vec<String>, durations: Vec<Duration>) -> Player {
+        Player {
+            frames,
+            durations,
+            speed: 1.0,
+            loop_count: 0,
+            frame: 0,
+            loops: 0,
+            due: Instant::now(),
+            dropped: 0,
+        }
+    }
+
+    // 2.0 plays twice as fast.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+    }
+
+    // Counts the loops played so far, so lowering it may end the animation right away.
+    pub fn set_loop_count(&mut self, loop_count: u32) {
+        self.loop_count = loop_count;
+    }
+
+    // The frame to show at `now`, None once the loops are done. The last frame of the last
+    // loop is never dropped, it is what stays on screen.
+    pub fn advance(&mut self, now: Instant) -> Option<&str> {
+        if self.finished() || self.frames.is_empty() {
+            return None;
+        }
+        // Too far behind to catch up with, e.g. after a suspend.
+        if now.saturating_duration_since(self.due) > self.loop_duration() {
+            self.due = now;
+        }
+        while self.due + self.duration(self.frame) <= now && !self.last() {
+            self.due += self.duration(self.frame);
+            self.step();
+            self.dropped += 1;
+        }
+
+        let shown = self.frame;
+        self.due += self.duration(shown);
+        self.step();
+
+        Some(&self.frames[shown])
+    }
+
+    // When advance has the next frame.
+    pub fn due(&self) -> Instant {
+        self.due
+    }
+
+    // Goes on from the next frame as if it were due now, after a pause.
+    pub fn resync(&mut self, now: Instant) {
+        self.due = now;
+    }
+
+    pub fn finished(&self) -> bool {
+        self.loop_count > 0 && self.loops >= self.loop_count
+    }
+
+    // The frame on screen counted from 1, and how many there are.
+    pub fn position(&self) -> (usize, usize) {
+        let count = self.frames.len();
+        let shown = match self.frame {
+            0 if self.loops == 0 => 0,
+            0 => count,
+            f => f,
+        };
+
+        (shown, count)
+    }
+
+    pub fn loops(&self) -> u32 {
+        self.loops
+    }
+
+    pub fn loop_count(&self) -> u32 {
+        self.loop_count
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    // Frames skipped to keep up.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    fn duration(&self, frame: usize) -> Duration {
+        let duration = self
+            .durations
+            .get(frame)
+            .or_else(|| self.durations.last())
+            .copied()
+            .unwrap_or_default();
+
+        duration.div_f64(self.speed).max(MIN_FRAME)
+    }
+
+    fn loop_duration(&self) -> Duration {
+        (0..self.frames.len()).map(|f| self.duration(f)).sum()
+    }
+
+    fn last(&self) -> bool {
+        self.loop_count > 0 && self.loops + 1 == self.loop_count && self.frame + 1 == self.frames.len()
+    }
+
+    fn step(&mut self) {
+        self.frame += 1;
+        if self.frame == self.frames.len() {
+            self.frame = 0;
+            self.loops += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn player(count: usize, durations: &[u64]) -> (Player, Instant) {
+        let frames = (1..=count).map(|f| format!("f{}", f)).collect();
+        let mut player = Player::new(frames, durations.iter().map(|d| ms(*d)).collect());
+        let start = Instant::now();
+        player.resync(start);
+
+        (player, start)
+    }
+
+    #[test]
+    fn every_frame_is_shown_for_its_own_duration() {
+        // f4 has no delay of its own and gets the last one.
+        let (mut player, t0) = player(4, &[100, 200, 300]);
+
+        assert_eq!(player.advance(t0), Some("f1"));
+        assert_eq!(player.due(), t0 + ms(100));
+        assert_eq!(player.advance(t0 + ms(100)), Some("f2"));
+        assert_eq!(player.due(), t0 + ms(300));
+        assert_eq!(player.advance(t0 + ms(300)), Some("f3"));
+        assert_eq!(player.due(), t0 + ms(600));
+        assert_eq!(player.advance(t0 + ms(600)), Some("f4"));
+        assert_eq!(player.due(), t0 + ms(900));
+        assert_eq!(player.advance(t0 + ms(900)), Some("f1"));
+        assert_eq!(player.loops(), 1);
+        assert_eq!(player.dropped(), 0);
+    }
+
+    #[test]
+    fn frames_that_are_over_are_dropped() {
+        let (mut player, t0) = player(4, &[100]);
+
+        assert_eq!(player.advance(t0), Some("f1"));
+        // f2 was due at 100 and over at 200.
+        assert_eq!(player.advance(t0 + ms(250)), Some("f3"));
+        // Still counted from when f3 was due, not from when it was shown.
+        assert_eq!(player.due(), t0 + ms(300));
+        assert_eq!(player.dropped(), 1);
+    }
+
+    #[test]
+    fn the_last_frame_of_the_last_loop_is_never_dropped() {
+        let (mut forever, t0) = player(3, &[100]);
+        forever.advance(t0);
+        assert_eq!(forever.advance(t0 + ms(390)), Some("f1"));
+        assert_eq!(forever.dropped(), 2);
+
+        let (mut once, t0) = player(3, &[100]);
+        once.set_loop_count(1);
+        once.advance(t0);
+        assert_eq!(once.advance(t0 + ms(390)), Some("f3"));
+        assert_eq!(once.dropped(), 1);
+        assert!(once.finished());
+        assert_eq!(once.advance(t0 + ms(1000)), None);
+        assert_eq!(once.position(), (3, 3));
+    }
+
+    #[test]
+    fn speed_divides_the_durations() {
+        let (mut player, t0) = player(3, &[100]);
+        player.set_speed(2.0);
+        assert_eq!(player.advance(t0), Some("f1"));
+        assert_eq!(player.due(), t0 + ms(50));
+
+        // Never faster than a GIF can tell.
+        player.set_speed(100.0);
+        assert_eq!(player.advance(t0 + ms(50)), Some("f2"));
+        assert_eq!(player.due(), t0 + ms(50) + MIN_FRAME);
+    }
+
+    #[test]
+    fn resync_goes_on_without_dropping_frames() {
+        let (mut player, t0) = player(3, &[100]);
+        assert_eq!(player.advance(t0), Some("f1"));
+
+        // Paused for 150ms, so f2 and half of f3 would be over.
+        player.resync(t0 + ms(250));
+        assert_eq!(player.advance(t0 + ms(250)), Some("f2"));
+        assert_eq!(player.due(), t0 + ms(350));
+        assert_eq!(player.dropped(), 0);
+    }
+}
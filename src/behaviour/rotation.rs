@@ -0,0 +1,870 @@
+use crate::behaviour::frames;
+use crate::behaviour::player::Player;
+use crate::cache;
+use crate::cancel::Cancel;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::function::{get_de, is_wayland, De, Previous};
+use crate::history::History;
+use crate::log::Log;
+use crate::playlist::Playlist;
+use crate::runner::{CommandRunner, SystemRunner};
+use crate::tasker::control::{Command, Request};
+use crate::x11::watch_screen_changes;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+// How often monitors are detected again where nothing tells us they changed.
+const HOTPLUG_POLL: Duration = Duration::from_secs(10);
+
+// How the rotation learns that monitors were plugged, unplugged or moved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hotplug {
+    // RandR tells X11 clients, the change comes in as a Command::Refresh.
+    Events,
+    // Wayland has no common way to tell, the monitors are detected again every so often.
+    Poll(Duration),
+    // The monitors of the config stay the ones there are.
+    Off,
+}
+
+// What the wait does after a control command.
+enum Step {
+    // Pick new wallpapers now.
+    Change,
+    // Something else is on screen, give it a full interval.
+    Restart,
+    Continue,
+    // We are shutting down.
+    Stop,
+}
+
+// Where the rotation takes the time from. A synthetic clock stands still until the rotation
+// waits and then moves by as long as it waits, so frames and intervals come without sleeping.
+#[derive(Debug, Clone)]
+pub enum Clock {
+    System,
+    Synthetic(Arc<Mutex<Instant>>),
+}
+
+impl Clock {
+    pub fn synthetic(start: Instant) -> Clock {
+        Clock::Synthetic(Arc::new(Mutex::new(start)))
+    }
+
+    pub fn now(&self) -> Instant {
+        match self {
+            Clock::System => Instant::now(),
+            Clock::Synthetic(now) => *now.lock().unwrap_or_else(|e| e.into_inner()),
+        }
+    }
+
+    fn advance(&self, by: Duration) {
+        if let Clock::Synthetic(now) = self {
+            *now.lock().unwrap_or_else(|e| e.into_inner()) += by;
+        }
+    }
+}
+
+// Changes the wallpapers every interval, or plays a video or gif, until a Quit comes in.
+pub struct Rotator {
+    config: Config,
+    de: Box<dyn De>,
+    // Splits videos and gifs into frames.
+    runner: Arc<dyn CommandRunner>,
+    // Where the frames are cached.
+    frames_dir: PathBuf,
+    playlist: Playlist,
+    history: History,
+    requests: Sender<Request>,
+    control: Receiver<Request>,
+    paused: bool,
+    clock: Clock,
+    deadline: Instant,
+    hotplug: Hotplug,
+    // When monitors are detected again with Hotplug::Poll, kept across waits as frames come
+    // faster than that.
+    poll: Instant,
+    // The video or gif being played, None while rotating.
+    player: Option<Player>,
+    // Keeps `cache clear` away from the frames played.
+    held: Option<cache::Hold>,
+    // Put back when we stop, with --restore-on-exit.
+    previous: Option<Previous>,
+    log: Log,
+    // Stops an extraction that is still running when we shut down.
+    cancel: Cancel,
+}
+
+impl Rotator {
+    // Uses the backend of the config, or the detected one.
+    pub fn new(config: Config) -> Result<Rotator> {
+        let de = get_de(&config.backend)?;
+        Rotator::with_backend(config, de)
+    }
+
+    pub fn with_backend(config: Config, de: Box<dyn De>) -> Result<Rotator> {
+        if !config.is_video && !config.is_gif && config.resolutions().is_empty() {
+            Err(Error::Config("Please specify the resolution.".to_string()))?
+        }
+
+        let previous = if config.restore_on_exit { de.previous() } else { None };
+
+        let (requests, control) = channel();
+        Ok(Rotator {
+            playlist: Playlist::new(config.order),
+            history: History::load(),
+            config,
+            de,
+            runner: Arc::new(SystemRunner),
+            frames_dir: cache::frames_dir(),
+            requests,
+            control,
+            paused: false,
+            clock: Clock::System,
+            deadline: Instant::now(),
+            hotplug: if is_wayland() { Hotplug::Poll(HOTPLUG_POLL) } else { Hotplug::Events },
+            poll: Instant::now() + HOTPLUG_POLL,
+            player: None,
+            held: None,
+            previous,
+            log: Log::silent(),
+            cancel: Cancel::new(),
+        })
+    }
+
+    // ffmpeg and convert run through `runner` instead of the real programs.
+    pub fn with_runner(mut self, runner: Arc<dyn CommandRunner>) -> Rotator {
+        self.runner = runner;
+        self
+    }
+
+    // Caches frames in `dir` instead of cache::frames_dir().
+    pub fn with_frames_dir(mut self, dir: PathBuf) -> Rotator {
+        self.frames_dir = dir;
+        self
+    }
+
+    // Keeps the wallpapers shown in `history` instead of the one of the state directory.
+    pub fn with_history(mut self, history: History) -> Rotator {
+        self.history = history;
+        self
+    }
+
+    // Frames and intervals are timed by `clock`, see Clock::synthetic.
+    pub fn with_clock(mut self, clock: Clock) -> Rotator {
+        self.deadline = clock.now();
+        self.poll = clock.now() + HOTPLUG_POLL;
+        self.clock = clock;
+        self
+    }
+
+    // Gets what happens while running: refused wallpapers, layout and profile changes...
+    pub fn with_log(mut self, log: Log) -> Rotator {
+        if self.config.restore_on_exit && self.previous.is_none() {
+            log.message("Couldn't read the current wallpaper, it won't be restored on exit");
+        }
+        self.log = log;
+        self
+    }
+
+    // Setting `cancel` kills ffmpeg or convert when they are splitting a video or gif, the
+    // rotation stops with the next Quit.
+    pub fn with_cancel(mut self, cancel: Cancel) -> Rotator {
+        self.cancel = cancel;
+        self
+    }
+
+    // Defaults to events on X11 and a poll every 10s on Wayland.
+    pub fn with_hotplug(mut self, hotplug: Hotplug) -> Rotator {
+        if let Hotplug::Poll(every) = hotplug {
+            self.poll = self.clock.now() + every;
+        }
+        self.hotplug = hotplug;
+        self
+    }
+
+    // Where commands for the rotation go, e.g. from the control socket or signals.
+    pub fn requests(&self) -> Sender<Request> {
+        self.requests.clone()
+    }
+
+    // Returns once asked to quit, with --restore-on-exit the previous wallpaper is put back.
+    // A wallpaper the backend refuses is reported and the rotation goes on.
+    pub fn run(&mut self) -> Result<()> {
+        self.watch_monitors();
+        let result = self.rotate();
+        match self.previous.take() {
+            Some(previous) => result.and(self.de.restore(previous)),
+            None => result,
+        }
+    }
+
+    // Falls back to polling when the X server can't be watched.
+    fn watch_monitors(&mut self) {
+        if self.hotplug != Hotplug::Events {
+            return;
+        }
+
+        let requests = self.requests.clone();
+        let watching = watch_screen_changes(move || {
+            // Nobody reads the answer, the watcher stops with the rotation.
+            let (reply, _answer) = channel();
+            requests.send(Request { command: Command::Refresh, reply }).is_ok()
+        });
+        if let Err(e) = watching {
+            self.log.message(format!("Can't watch monitor changes ({}), checking every {}s", e, HOTPLUG_POLL.as_secs()));
+            self.hotplug = Hotplug::Poll(HOTPLUG_POLL);
+            self.poll = self.clock.now() + HOTPLUG_POLL;
+        }
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        if self.playing() {
+            match self.load_frames() {
+                // Shutting down before the first frame.
+                Err(_e) if self.cancel.is_cancelled() => return Ok(()),
+                result => result?,
+            }
+        }
+
+        loop {
+            self.change()?;
+            if !self.wait() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn change(&mut self) -> Result<()> {
+        if let Some(player) = self.player.as_mut() {
+            if let Some(frame) = player.advance(self.clock.now()).map(|f| f.to_string()) {
+                let _ = self.show(vec!(frame));
+            }
+            return Ok(());
+        }
+
+        let dirs = wallpaper_dirs(&self.config)?;
+        let monitors = dirs.iter().map(|d| d.0.clone()).collect::<Vec<String>>();
+        let mut rand_images: Vec<String> = vec!();
+        for (_, _, dir) in dirs.iter() {
+            rand_images.push(self.playlist.next(dir));
+        }
+
+        self.remember(&monitors, &rand_images);
+        let _ = self.show(rand_images);
+
+        Ok(())
+    }
+
+    // Logs why the backend refused, the next wallpaper may get through.
+    fn show(&self, wallpapers: Vec<String>) -> Result<()> {
+        let result = self.de.set_wallpaper(wallpapers, self.config.mode);
+        if let Err(e) = &result {
+            self.log.message(e);
+        }
+
+        result
+    }
+
+    fn remember(&mut self, monitors: &[String], wallpapers: &[String]) {
+        if let Err(e) = self.history.push(monitors, wallpapers) {
+            self.log.message(format!("Couldn't save history {}", e));
+        }
+    }
+
+    // Sleeps until the next wallpaper or frame is due, but comes back early when a monitor is
+    // plugged or unplugged, so the next wallpaper is sent to the new layout right away.
+    // While paused, or once the loops of a video are played, only a layout change or a command
+    // brings new wallpapers.
+    // Returns false once asked to stop.
+    fn wait(&mut self) -> bool {
+        self.deadline = self.next_deadline();
+
+        loop {
+            let now = self.clock.now();
+            let idle = self.paused || self.player.as_ref().map(|p| p.finished()).unwrap_or(false);
+            if !idle && now >= self.deadline {
+                return true;
+            }
+            if let Hotplug::Poll(every) = self.hotplug {
+                if now >= self.poll {
+                    self.poll = now + every;
+                    if self.refresh_monitors() {
+                        return true;
+                    }
+                }
+            }
+            let poll = match self.hotplug {
+                Hotplug::Poll(_) => Some(self.poll),
+                _ => None,
+            };
+
+            let until = match (idle, poll) {
+                (true, poll) => poll,
+                (false, Some(poll)) => Some(self.deadline.min(poll)),
+                (false, None) => Some(self.deadline),
+            };
+            if let Some(request) = self.receive(until.map(|u| u.saturating_duration_since(now))) {
+                match self.handle(request) {
+                    Step::Change => return true,
+                    Step::Stop => return false,
+                    Step::Restart => {
+                        if let Some(player) = self.player.as_mut() {
+                            player.resync(self.clock.now());
+                        }
+                        self.deadline = self.next_deadline();
+                    }
+                    Step::Continue => {}
+                }
+            }
+        }
+    }
+
+    // Returns whether the layout changed.
+    fn refresh_monitors(&mut self) -> bool {
+        if !self.config.refresh_monitors() {
+            return false;
+        }
+        self.log.message(format!("Monitor layout changed, {} monitors", self.config.monitors.len()));
+        self.switch_profile();
+
+        true
+    }
+
+    // Follows the profile matching the new layout, unless one was pinned.
+    fn switch_profile(&mut self) {
+        match self.config.switch_profile() {
+            Ok(true) => {
+                match &self.config.active_profile {
+                    Some(p) => self.log.message(format!("Switched to profile {}", p)),
+                    None => self.log.message("No profile matches, back to the default settings"),
+                }
+                if !self.playing() {
+                    self.playlist = Playlist::new(self.config.order);
+                }
+            }
+            Ok(false) => {}
+            Err(e) => self.log.message(format!("Config error:{}", e)),
+        }
+    }
+
+    // We hold a sender ourselves, so the channel never disconnects.
+    // Without a timeout only a command ends the wait.
+    fn receive(&self, timeout: Option<Duration>) -> Option<Request> {
+        match (&self.clock, timeout) {
+            (Clock::Synthetic(_), Some(t)) => match self.control.try_recv() {
+                Ok(request) => Some(request),
+                Err(_e) => {
+                    self.clock.advance(t);
+                    None
+                }
+            },
+            (_, Some(t)) => self.control.recv_timeout(t).ok(),
+            (_, None) => self.control.recv().ok(),
+        }
+    }
+
+    fn handle(&mut self, request: Request) -> Step {
+        let monitors = monitor_names(&self.config);
+
+        let (answer, step) = match request.command {
+            Command::Next | Command::Prev | Command::History if self.playing() => {
+                ("error: not available for videos and gifs".to_string(), Step::Continue)
+            }
+            Command::Next => match self.history.next(&monitors) {
+                Some(w) => (answer(self.show(w)), Step::Restart),
+                None => ("ok".to_string(), Step::Change),
+            },
+            Command::Prev => match self.history.prev(&monitors) {
+                Some(w) => (answer(self.show(w)), Step::Restart),
+                None => ("error: no older wallpaper".to_string(), Step::Continue),
+            },
+            Command::History => (self.history.list(&monitors), Step::Continue),
+            Command::Pause => {
+                self.paused = true;
+                ("ok".to_string(), Step::Continue)
+            }
+            Command::Resume => {
+                self.paused = false;
+                ("ok".to_string(), Step::Restart)
+            }
+            Command::Toggle => {
+                self.paused = !self.paused;
+                let step = if self.paused { Step::Continue } else { Step::Restart };
+                ("ok".to_string(), step)
+            }
+            Command::Set(path) => self.set(&path, &monitors),
+            Command::Status => (self.status(&monitors), Step::Continue),
+            Command::Reload => match self.config.reload() {
+                Ok(()) if self.playing() => return self.play_answered(request.reply),
+                Ok(()) => {
+                    self.playlist = Playlist::new(self.config.order);
+                    ("ok".to_string(), Step::Change)
+                }
+                Err(e) => (format!("error: {}", e), Step::Continue),
+            },
+            Command::Refresh => match self.refresh_monitors() {
+                true => ("ok".to_string(), Step::Change),
+                false => ("ok".to_string(), Step::Continue),
+            },
+            Command::Quit => ("ok".to_string(), Step::Stop),
+            Command::Interval(secs) => {
+                self.config.set_interval(secs);
+                ("ok".to_string(), Step::Restart)
+            }
+            Command::Speed(speed) => {
+                self.config.speed = speed;
+                if let Some(player) = self.player.as_mut() {
+                    player.set_speed(speed);
+                }
+                ("ok".to_string(), Step::Restart)
+            }
+            Command::LoopCount(count) => {
+                self.config.loop_count = count;
+                if let Some(player) = self.player.as_mut() {
+                    player.set_loop_count(count);
+                }
+                ("ok".to_string(), Step::Continue)
+            }
+            Command::Gif(fps, path) => {
+                self.config.is_video = false;
+                self.config.is_gif = true;
+                self.config.fps = fps;
+                self.config.file = Some(path);
+                return self.play_answered(request.reply);
+            }
+            Command::Video(path) => {
+                self.config.is_video = true;
+                self.config.is_gif = false;
+                // Plays at its own rate, not at the fps of a gif before.
+                self.config.fps = 0;
+                self.config.file = Some(path);
+                return self.play_answered(request.reply);
+            }
+        };
+
+        // The client may have given up already.
+        let _ = request.reply.send(answer);
+
+        step
+    }
+
+    fn playing(&self) -> bool {
+        self.config.is_video || self.config.is_gif
+    }
+
+    fn next_deadline(&self) -> Instant {
+        match &self.player {
+            Some(player) => player.due(),
+            None => self.clock.now() + Duration::from_secs(self.config.interval as u64),
+        }
+    }
+
+    fn load_frames(&mut self) -> Result<()> {
+        self.player = None;
+        let frames = frames::extract(&self.config, &self.frames_dir, &*self.runner, &self.log, &self.cancel)?;
+        if frames.is_empty() {
+            let program = if self.config.is_video { "ffmpeg" } else { frames::imagemagick() };
+            let file = self.config.file.clone().unwrap_or_default();
+            Err(Error::dependency(program, format!("no frames could be extracted from {}", file)))?
+        }
+
+        self.held = cache::hold(&frames);
+        let mut player = Player::new(frames, frames::durations(&self.config, &*self.runner, &self.log));
+        player.set_speed(self.config.speed);
+        player.set_loop_count(self.config.loop_count);
+        player.resync(self.clock.now());
+        self.player = Some(player);
+
+        Ok(())
+    }
+
+    // Extracting the frames may take longer than a client waits, so only a missing file is
+    // answered as an error, what goes wrong later is logged.
+    fn play_answered(&mut self, reply: Sender<String>) -> Step {
+        let file = self.config.file.clone().unwrap_or_default();
+        if !Path::new(&file).is_file() {
+            self.stop_playing();
+            let _ = reply.send(format!("error: {} is not a file", file));
+            return Step::Continue;
+        }
+        let _ = reply.send("ok".to_string());
+
+        if let Err(e) = self.load_frames() {
+            self.log.message(format!("Couldn't play {}:{}", file, e));
+            self.stop_playing();
+            return Step::Continue;
+        }
+
+        Step::Change
+    }
+
+    fn stop_playing(&mut self) {
+        self.config.is_video = false;
+        self.config.is_gif = false;
+        self.config.file = None;
+        self.player = None;
+        self.held = None;
+    }
+
+    fn set(&mut self, path: &str, monitors: &[String]) -> (String, Step) {
+        let metadata = match fs::metadata(path) {
+            Ok(m) => m,
+            Err(e) => return (format!("error: {}:{}", path, e), Step::Continue),
+        };
+        self.stop_playing();
+
+        if metadata.is_dir() {
+            self.config.set_dir(path);
+            self.playlist = Playlist::new(self.config.order);
+            return ("ok".to_string(), Step::Change);
+        }
+
+        let wallpapers = vec![path.to_string(); monitors.len()];
+        self.remember(monitors, &wallpapers);
+
+        (answer(self.show(wallpapers)), Step::Restart)
+    }
+
+    fn status(&self, monitors: &[String]) -> String {
+        let mut status = String::new();
+        if self.paused {
+            status.push_str("State: paused\n");
+        } else if self.player.as_ref().map(|p| p.finished()).unwrap_or(false) {
+            status.push_str("State: stopped on the last frame\n");
+        } else {
+            let left = self.deadline.saturating_duration_since(self.clock.now());
+            status.push_str(&format!("State: running, next change in {}s\n", left.as_secs()));
+        }
+        if let (Some(file), Some(player)) = (&self.config.file, &self.player) {
+            let (shown, count) = player.position();
+            let loops = match player.loop_count() {
+                0 => format!("loop {}", player.loops() + 1),
+                n if player.finished() => format!("done after {} loops", n),
+                n => format!("loop {}/{}", player.loops() + 1, n),
+            };
+            status.push_str(&format!(
+                "Playing: {} (frame {}/{}, {}, speed {}x, {} dropped)\n",
+                file,
+                shown,
+                count,
+                loops,
+                player.speed(),
+                player.dropped()
+            ));
+        }
+        if let Some(p) = &self.config.active_profile {
+            status.push_str(&format!("Profile: {}\n", p));
+        }
+        status.push_str(&format!("Directory: {}\n", self.config.dir));
+        status.push_str(&format!("Interval: {}s\n", self.config.interval));
+        status.push_str(&format!("Scale mode: {}\n", self.config.mode.name()));
+        status.push_str(&format!("Order: {}\n", self.config.order.name()));
+
+        let current = self.history.current(monitors).unwrap_or_default();
+        for (i, m) in monitors.iter().enumerate() {
+            status.push_str(&format!("{}: {}\n", m, current.get(i).map(|w| &w[..]).unwrap_or("-")));
+        }
+
+        status
+    }
+}
+
+fn answer(result: Result<()>) -> String {
+    match result {
+        Ok(()) => "ok".to_string(),
+        Err(e) => format!("error: {}", e),
+    }
+}
+
+// The output names, #<index> for resolutions without a detected monitor.
+pub fn monitor_names(config: &Config) -> Vec<String> {
+    (0..config.resolutions().len())
+        .map(|i| config.monitors.get(i).map(|m| m.name.clone()).unwrap_or_else(|| format!("#{}", i)))
+        .collect()
+}
+
+// (monitor, resolution, directory)
+pub type MonitorDir = (String, String, String);
+
+// Picks the directory of every monitor, the first one holding files wins:
+// <dir>/<output name>, <dir>/<WxH>, <dir>/portrait or <dir>/landscape, then <dir> itself.
+pub fn wallpaper_dirs(config: &Config) -> Result<Vec<MonitorDir>> {
+    if let Err(e) = fs::read_dir(&config.dir) {
+        Err(Error::io(&config.dir, e))?
+    }
+
+    let mut dirs = vec!();
+    for (i, r) in config.resolutions().iter().enumerate() {
+        let name = config.monitors.get(i).map(|m| m.name.clone());
+        let mut candidates = vec!();
+        if let Some(n) = &name {
+            candidates.push(n.clone());
+        }
+        candidates.push(r.clone());
+        if let Some((w, h)) = r.split_once('x') {
+            if let (Ok(w), Ok(h)) = (w.parse::<u32>(), h.parse::<u32>()) {
+                candidates.push(if w < h { "portrait" } else { "landscape" }.to_string());
+            }
+        }
+
+        let dir = candidates
+            .iter()
+            .map(|c| Path::new(&config.dir).join(c).display().to_string())
+            .find(|d| has_files(d))
+            .unwrap_or_else(|| config.dir.clone());
+        dirs.push((name.unwrap_or_else(|| format!("#{}", i)), r.clone(), dir));
+    }
+
+    Ok(dirs)
+}
+
+fn has_files(dir: &str) -> bool {
+    match fs::read_dir(dir) {
+        Ok(entries) => entries.flatten().any(|e| e.path().is_file()),
+        Err(_e) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache;
+    use crate::dependency::Dependency;
+    use crate::function::{gen_rand_string, ScaleMode};
+    use crate::monitor::Monitor;
+    use crate::runner::{FakeRunner, Process};
+    use std::env;
+    use std::thread;
+
+    // Remembers every set of wallpapers it is given.
+    #[derive(Clone, Default)]
+    struct Recording {
+        shown: Arc<Mutex<Vec<Vec<String>>>>,
+        // Told about every set, so a test knows it is on screen without sleeping.
+        told: Arc<Mutex<Option<Sender<()>>>>,
+    }
+
+    impl Recording {
+        fn shown(&self) -> Vec<Vec<String>> {
+            self.shown.lock().unwrap().clone()
+        }
+
+        fn tell(&self) -> Receiver<()> {
+            let (told, on_screen) = channel();
+            *self.told.lock().unwrap() = Some(told);
+            on_screen
+        }
+    }
+
+    impl De for Recording {
+        fn wallpaper_dependencies(&self) -> Vec<Dependency> {
+            vec!()
+        }
+
+        fn set_wallpaper(&self, wallpaper_paths: Vec<String>, _mode: ScaleMode) -> Result<()> {
+            self.shown.lock().unwrap().push(wallpaper_paths);
+            if let Some(told) = self.told.lock().unwrap().as_ref() {
+                let _ = told.send(());
+            }
+            Ok(())
+        }
+    }
+
+    // Writes three frames where convert is told to.
+    struct Splitter(FakeRunner);
+
+    impl CommandRunner for Splitter {
+        fn run(&self, program: &str, args: &[&str]) -> Result<String> {
+            let pattern = args.last().copied().unwrap_or_default();
+            for frame in 1..=3 {
+                let path = pattern.replace("%09d", &format!("{:09}", frame));
+                fs::write(&path, "jpg").map_err(|e| Error::io(&path, e))?;
+            }
+            self.0.run(program, args)
+        }
+
+        fn spawn(&self, program: &str, args: &[&str]) -> Result<Box<dyn Process>> {
+            self.0.spawn(program, args)
+        }
+
+        fn kill(&self, program: &str, pid: u32) -> Result<()> {
+            self.0.kill(program, pid)
+        }
+    }
+
+    #[test]
+    fn every_monitor_gets_the_most_specific_dir_with_files() {
+        let dir = env::temp_dir().join(format!("wallpaper-test-{}", gen_rand_string()));
+        for (sub, file) in [("DP-1", "a.jpg"), ("1080x1920", "b.jpg"), ("landscape", "c.jpg"), ("HDMI-1/empty", "")] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+            if !file.is_empty() {
+                fs::write(dir.join(sub).join(file), "jpg").unwrap();
+            }
+        }
+        let config = Config::builder()
+            .dir(&dir.display().to_string())
+            .monitors(vec!(
+                Monitor::new("DP-1", 2560, 1440, 0, 0),
+                // Its own dir only holds a dir.
+                Monitor::new("HDMI-1", 1080, 1920, 2560, 0),
+                Monitor::new("eDP-1", 1920, 1080, 3640, 0),
+                Monitor::new("DP-2", 1200, 1920, 5560, 0),
+            ))
+            .build()
+            .unwrap();
+
+        let dirs = wallpaper_dirs(&config).unwrap();
+
+        let root = config.dir().to_string();
+        let sub = |s: &str| dir.join(s).display().to_string();
+        assert_eq!(
+            dirs,
+            vec!(
+                ("DP-1".to_string(), "2560x1440".to_string(), sub("DP-1")),
+                ("HDMI-1".to_string(), "1080x1920".to_string(), sub("1080x1920")),
+                ("eDP-1".to_string(), "1920x1080".to_string(), sub("landscape")),
+                ("DP-2".to_string(), "1200x1920".to_string(), root),
+            )
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    // A Splitter that extracts only once let through.
+    struct Gated(Splitter, Mutex<Receiver<()>>);
+
+    impl CommandRunner for Gated {
+        fn run(&self, program: &str, args: &[&str]) -> Result<String> {
+            // Longer than a client waits for its answer.
+            let gate = self.1.lock().unwrap().recv_timeout(Duration::from_secs(10));
+            gate.map_err(|_e| Error::dependency(program, "never let through"))?;
+            self.0.run(program, args)
+        }
+
+        fn spawn(&self, program: &str, args: &[&str]) -> Result<Box<dyn Process>> {
+            self.0.spawn(program, args)
+        }
+
+        fn kill(&self, program: &str, pid: u32) -> Result<()> {
+            self.0.kill(program, pid)
+        }
+    }
+
+    #[test]
+    fn a_gif_is_answered_before_its_frames_are_extracted() {
+        let dir = env::temp_dir().join(format!("wallpaper-test-{}", gen_rand_string()));
+        fs::create_dir_all(&dir).unwrap();
+        let gifs = ["a.gif", "b.gif"].iter().map(|g| dir.join(g).display().to_string()).collect::<Vec<String>>();
+        for gif in gifs.iter() {
+            fs::write(gif, gen_rand_string()).unwrap();
+        }
+        let config = Config::builder()
+            .dir(&dir.display().to_string())
+            .gif(&gifs[0], 20)
+            .loop_count(1)
+            .monitors(vec!())
+            .build()
+            .unwrap();
+
+        let de = Recording::default();
+        let on_screen = de.tell();
+        let (gate, gated) = channel();
+        let mut rotator = Rotator::with_backend(config, Box::new(de.clone()))
+            .unwrap()
+            .with_runner(Arc::new(Gated(Splitter(FakeRunner::new()), Mutex::new(gated))))
+            .with_hotplug(Hotplug::Off)
+            .with_clock(Clock::synthetic(Instant::now()))
+            .with_history(History::load_from(dir.join("history.json")))
+            .with_frames_dir(dir.join("frames"));
+        let requests = rotator.requests();
+        let ask = move |command: Command| {
+            let (reply, answer) = channel();
+            requests.send(Request { command, reply }).unwrap();
+            answer.recv_timeout(Duration::from_secs(5)).unwrap()
+        };
+        let b = gifs[1].clone();
+        let missing = dir.join("missing.gif").display().to_string();
+        let client = thread::spawn(move || {
+            gate.send(()).unwrap();
+            for _ in 0..3 {
+                on_screen.recv_timeout(Duration::from_secs(5)).unwrap();
+            }
+            // Still extracting b.gif when the answer comes.
+            let answers = vec!(ask(Command::Gif(20, missing)), ask(Command::Gif(20, b)));
+            gate.send(()).unwrap();
+            for _ in 0..3 {
+                on_screen.recv_timeout(Duration::from_secs(5)).unwrap();
+            }
+            ask(Command::Quit);
+            answers
+        });
+        rotator.run().unwrap();
+        let answers = client.join().unwrap();
+
+        assert!(answers[0].starts_with("error: ") && answers[0].ends_with("missing.gif is not a file"), "{}", answers[0]);
+        assert_eq!(answers[1], "ok");
+        assert_eq!(de.shown().len(), 6);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn every_frame_of_a_gif_reaches_the_backend_once() {
+        let dir = env::temp_dir().join(format!("wallpaper-test-{}", gen_rand_string()));
+        fs::create_dir_all(&dir).unwrap();
+        // Its own content, so the frames aren't in the cache yet.
+        let gif = dir.join("a.gif");
+        fs::write(&gif, gen_rand_string()).unwrap();
+        let gif = gif.display().to_string();
+        let config = Config::builder()
+            .dir(&dir.display().to_string())
+            .gif(&gif, 20)
+            .loop_count(1)
+            .monitors(vec!())
+            .build()
+            .unwrap();
+
+        let de = Recording::default();
+        let on_screen = de.tell();
+        let runner = FakeRunner::new();
+        let start = Instant::now();
+        let clock = Clock::synthetic(start);
+        let mut rotator = Rotator::with_backend(config, Box::new(de.clone()))
+            .unwrap()
+            .with_runner(Arc::new(Splitter(runner.clone())))
+            .with_hotplug(Hotplug::Off)
+            .with_clock(clock.clone())
+            .with_history(History::load_from(dir.join("history.json")))
+            .with_frames_dir(dir.join("frames"));
+        let requests = rotator.requests();
+        let ask = move |command: Command| {
+            let (reply, answer) = channel();
+            requests.send(Request { command, reply }).unwrap();
+            answer.recv().unwrap()
+        };
+        let quit = thread::spawn(move || {
+            for _ in 0..3 {
+                on_screen.recv_timeout(Duration::from_secs(5)).unwrap();
+            }
+            // Nothing more comes once the loop is played, the rotation only waits for commands.
+            let status = ask(Command::Status);
+            ask(Command::Quit);
+            status
+        });
+        rotator.run().unwrap();
+        let status = quit.join().unwrap();
+
+        let frames = dir.join("frames").join(cache::key(&gif, None, 0).unwrap());
+        let frame = |f: u32| vec!(format!("{}/filename{:09}.jpg", frames.display(), f));
+        assert_eq!(de.shown(), vec!(frame(1), frame(2), frame(3)));
+        assert!(status.contains("State: stopped on the last frame"), "{}", status);
+        // 50ms for each of the first two frames at 20 fps, the last one stays.
+        assert_eq!(clock.now() - start, Duration::from_millis(100));
+        assert_eq!(runner.calls().len(), 1);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,359 @@
+use crate::error::{Error, Result};
+use crate::function::{gen_rand_string, get_cache_dir};
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::{getpid, Pid};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+// Holds the path of the video or gif next to its frames, for cache ls.
+const SOURCE_FILE: &str = "source";
+
+// Frames of the videos and gifs played, in frames/<key>/. An entry only gets its name once
+// all its frames are there, so an interrupted extraction is never picked up.
+pub fn frames_dir() -> PathBuf {
+    get_cache_dir().join("frames")
+}
+
+// sha256 of the content, the resolution the frames were scaled to ("original" when they
+// weren't) and the rate they were sampled at ("all" for every frame).
+pub fn key(file: &str, resolution: Option<&str>, fps: u16) -> Result<String> {
+    let mut reader = File::open(file).map_err(|e| Error::io(file, e))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec!(0; 1 << 16);
+    loop {
+        let read = reader.read(&mut buffer).map_err(|e| Error::io(file, e))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    let hash = hasher.finalize().iter().take(16).map(|b| format!("{:02x}", b)).collect::<String>();
+    let fps = match fps {
+        0 => "all".to_string(),
+        f => f.to_string(),
+    };
+
+    Ok(format!("{}-{}-{}", hash, resolution.unwrap_or("original"), fps))
+}
+
+// The frames of an entry of `dir` (frames_dir() unless told otherwise) in playing order, None
+// when it hasn't been extracted yet.
+pub fn lookup(dir: &Path, key: &str) -> Option<Vec<String>> {
+    let frames = frame_files(&dir.join(key)).ok()?;
+
+    Some(frames).filter(|f| !f.is_empty())
+}
+
+// Runs `extract` into a scratch directory that becomes the entry of `dir` once it returns.
+pub fn store<F>(dir: &Path, key: &str, source: &str, extract: F) -> Result<Vec<String>>
+where
+    F: FnOnce(&Path) -> Result<()>,
+{
+    remove_stale(dir);
+    // Named after us, so it is known to be left behind once we are gone.
+    let scratch = dir.join(format!(".{}-{}-{}", key, getpid(), gen_rand_string()));
+    fs::create_dir_all(&scratch).map_err(|e| Error::io(scratch.display(), e))?;
+
+    let entry = dir.join(key);
+    let stored = extract(&scratch)
+        .and_then(|()| fs::write(scratch.join(SOURCE_FILE), source).map_err(|e| Error::io(scratch.display(), e)))
+        .and_then(|()| frame_files(&scratch))
+        .and_then(|frames| match frames.is_empty() {
+            // Nothing to keep, the caller tells why.
+            true => Ok(false),
+            // Another instance may have stored the same entry meanwhile, theirs is kept.
+            false => match fs::rename(&scratch, &entry) {
+                Err(_e) if entry.is_dir() => Ok(true),
+                result => result.map(|()| true).map_err(|e| Error::io(entry.display(), e)),
+            },
+        });
+    // Gone after the rename, left behind otherwise.
+    let _ = fs::remove_dir_all(&scratch);
+
+    match stored? {
+        true => frame_files(&entry),
+        false => Ok(vec!()),
+    }
+}
+
+pub struct Entry {
+    pub key: String,
+    pub source: String,
+    pub frames: usize,
+    pub size: u64,
+}
+
+// Marks the entry of `frames` as playing for as long as it lives, `clear` leaves it alone.
+// The mark of a process that is gone counts for nothing.
+pub struct Hold(PathBuf);
+
+pub fn hold(frames: &[String]) -> Option<Hold> {
+    let entry = Path::new(frames.first()?).parent()?;
+    let key = entry.file_name()?.to_string_lossy();
+    let mark = entry.parent()?.join(format!(".{}-held-{}", key, getpid()));
+
+    fs::write(&mark, "").ok().map(|()| Hold(mark))
+}
+
+impl Drop for Hold {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+// Whether a live process plays the entry.
+fn held(dir: &Path, key: &str) -> bool {
+    let prefix = format!(".{}-held-", key);
+    let read = match fs::read_dir(dir) {
+        Ok(r) => r,
+        Err(_e) => return false,
+    };
+
+    read.flatten().any(|e| {
+        let name = e.file_name().to_string_lossy().into_owned();
+        name.strip_prefix(&prefix).and_then(|p| p.parse::<i32>().ok()).map(alive).unwrap_or(false)
+    })
+}
+
+fn alive(pid: i32) -> bool {
+    !matches!(kill(Pid::from_raw(pid), None), Err(nix::Error::Sys(Errno::ESRCH)))
+}
+
+// Every complete entry of `dir`, by key.
+pub fn entries(dir: &Path) -> Result<Vec<Entry>> {
+    remove_stale(dir);
+    let read = match fs::read_dir(dir) {
+        Ok(r) => r,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec!()),
+        Err(e) => Err(Error::io(dir.display(), e))?,
+    };
+
+    let mut entries = vec!();
+    for e in read.flatten() {
+        let key = e.file_name().to_string_lossy().into_owned();
+        if key.starts_with('.') || !e.path().is_dir() {
+            continue;
+        }
+        entries.push(Entry {
+            source: fs::read_to_string(e.path().join(SOURCE_FILE)).unwrap_or_default(),
+            frames: frame_files(&e.path()).map(|f| f.len()).unwrap_or(0),
+            size: dir_size(&e.path()),
+            key,
+        });
+    }
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+
+    Ok(entries)
+}
+
+// Bytes used by the cache, scratch directories included.
+pub fn size(dir: &Path) -> u64 {
+    dir_size(dir)
+}
+
+// Removes every entry but the ones playing, see hold. Returns the bytes freed.
+pub fn clear(dir: &Path) -> Result<u64> {
+    let mut freed = 0;
+    for e in entries(dir)?.into_iter().filter(|e| !held(dir, &e.key)) {
+        let entry = dir.join(&e.key);
+        match fs::remove_dir_all(&entry) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::io(entry.display(), e))?,
+            _ => freed += e.size,
+        }
+    }
+
+    Ok(freed)
+}
+
+// Scratch directories of extractions that were killed along with their process, and the
+// holds they left.
+fn remove_stale(dir: &Path) {
+    let read = match fs::read_dir(dir) {
+        Ok(r) => r,
+        Err(_e) => return,
+    };
+
+    for e in read.flatten() {
+        let name = e.file_name().to_string_lossy().into_owned();
+        if !name.starts_with('.') {
+            continue;
+        }
+        if !e.path().is_dir() {
+            let pid = name.rsplit_once("-held-").and_then(|(_, p)| p.parse::<i32>().ok());
+            if pid.map(|p| !alive(p)).unwrap_or(false) {
+                let _ = fs::remove_file(e.path());
+            }
+            continue;
+        }
+        let pid = name.rsplit('-').nth(1).and_then(|p| p.parse::<i32>().ok());
+        let gone = match pid {
+            Some(p) => !alive(p),
+            // Made before scratch directories were named after their process.
+            None => true,
+        };
+        if gone {
+            let _ = fs::remove_dir_all(e.path());
+        }
+    }
+}
+
+fn frame_files(dir: &Path) -> Result<Vec<String>> {
+    let mut frames: Vec<String> = fs::read_dir(dir)
+        .map_err(|e| Error::io(dir.display(), e))?
+        .flatten()
+        .filter(|e| e.file_name() != SOURCE_FILE)
+        .map(|e| format!("{}", e.path().display()))
+        .collect();
+    frames.sort();
+
+    Ok(frames)
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    let read = match fs::read_dir(dir) {
+        Ok(r) => r,
+        Err(_e) => return 0,
+    };
+
+    read.flatten()
+        .map(|e| match e.file_type() {
+            Ok(t) if t.is_dir() => dir_size(&e.path()),
+            _ => e.metadata().map(|m| m.len()).unwrap_or(0),
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::frames;
+    use crate::cancel::Cancel;
+    use crate::config::Config;
+    use crate::log::Log;
+    use crate::runner::{CommandRunner, FakeRunner, Process};
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wallpaper-test-{}", gen_rand_string()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Writes two frames where convert is told to.
+    struct Splitter(FakeRunner);
+
+    impl CommandRunner for Splitter {
+        fn run(&self, program: &str, args: &[&str]) -> Result<String> {
+            let pattern = args.last().copied().unwrap_or_default();
+            for frame in 1..=2 {
+                let path = pattern.replace("%09d", &format!("{:09}", frame));
+                fs::write(&path, "jpg").map_err(|e| Error::io(&path, e))?;
+            }
+            self.0.run(program, args)
+        }
+
+        fn spawn(&self, program: &str, args: &[&str]) -> Result<Box<dyn Process>> {
+            self.0.spawn(program, args)
+        }
+
+        fn kill(&self, program: &str, pid: u32) -> Result<()> {
+            self.0.kill(program, pid)
+        }
+    }
+
+    // An entry with `count` frames of 3 bytes.
+    fn entry(dir: &Path, key: &str, count: u32) -> Vec<String> {
+        store(dir, key, "/w/a.gif", |scratch| {
+            for f in 1..=count {
+                fs::write(scratch.join(format!("filename{:09}.jpg", f)), "jpg").unwrap();
+            }
+            Ok(())
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn a_second_extract_reuses_the_entry() {
+        let dir = temp_dir();
+        let gif = dir.join("a.gif");
+        fs::write(&gif, gen_rand_string()).unwrap();
+        let config = Config::builder()
+            .dir(&dir.display().to_string())
+            .gif(&gif.display().to_string(), 0)
+            .monitors(vec!())
+            .build()
+            .unwrap();
+        let runner = FakeRunner::new();
+        let splitter = Splitter(runner.clone());
+        let extract = || {
+            frames::extract(&config, &dir.join("frames"), &splitter, &Log::silent(), &Cancel::new()).unwrap()
+        };
+
+        let first = extract();
+        let second = extract();
+
+        assert_eq!(first.len(), 2);
+        assert_eq!(first, second);
+        assert_eq!(runner.calls().len(), 1);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn clear_keeps_the_entry_playing() {
+        let dir = temp_dir();
+        let playing = entry(&dir, "aaa-original-all", 2);
+        entry(&dir, "bbb-1920x1080-25", 3);
+        let hold = hold(&playing).unwrap();
+
+        let listed = entries(&dir).unwrap();
+        assert_eq!(
+            listed.iter().map(|e| (e.key.as_str(), e.frames, e.size)).collect::<Vec<_>>(),
+            vec!(("aaa-original-all", 2, 6 + 8), ("bbb-1920x1080-25", 3, 9 + 8))
+        );
+        assert_eq!(listed[0].source, "/w/a.gif");
+        assert_eq!(size(&dir), 6 + 8 + 9 + 8);
+
+        assert_eq!(clear(&dir).unwrap(), 9 + 8);
+        assert_eq!(lookup(&dir, "aaa-original-all"), Some(playing));
+        assert_eq!(lookup(&dir, "bbb-1920x1080-25"), None);
+
+        drop(hold);
+        assert_eq!(clear(&dir).unwrap(), 6 + 8);
+        assert!(entries(&dir).unwrap().is_empty());
+        assert_eq!(size(&dir), 0);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn the_hold_of_a_dead_process_keeps_nothing() {
+        let dir = temp_dir();
+        entry(&dir, "aaa-original-all", 1);
+        fs::write(dir.join(".aaa-original-all-held-999999999"), "").unwrap();
+
+        assert_eq!(clear(&dir).unwrap(), 3 + 8);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn only_scratch_directories_of_dead_processes_are_removed() {
+        let dir = std::env::temp_dir().join(format!("wallpaper-test-{}", gen_rand_string()));
+        let ours = format!(".abc-original-all-{}-R1", getpid());
+        for name in [ours.as_str(), ".abc-original-all-999999999-R2", ".abc-original-all-R3", "abc-original-all"] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+        }
+
+        remove_stale(&dir);
+
+        let mut left = fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect::<Vec<String>>();
+        left.sort();
+        assert_eq!(left, vec!(ours, "abc-original-all".to_string()));
+        let _ = fs::remove_dir_all(dir);
+    }
+}
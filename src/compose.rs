@@ -0,0 +1,70 @@
+use crate::error::{Error, Result};
+use crate::function::ScaleMode;
+use crate::monitor::Monitor;
+use image::imageops::{self, FilterType};
+use image::{ImageError, RgbImage};
+use std::io;
+
+// Draws one image per monitor at its position on a canvas covering the whole screen.
+pub fn span(
+    wallpaper_paths: &[String],
+    monitors: &[Monitor],
+    width: u32,
+    height: u32,
+    mode: ScaleMode,
+) -> Result<RgbImage> {
+    let mut canvas = RgbImage::new(width, height);
+    for (i, m) in monitors.iter().enumerate() {
+        let path = &wallpaper_paths[i % wallpaper_paths.len()];
+        let img = open(path)?;
+        let img = render(&img, m.width, m.height, mode);
+        imageops::replace(&mut canvas, &img, m.x as i64, m.y as i64);
+    }
+
+    Ok(canvas)
+}
+
+// A picture that can't be decoded is as unreadable as a missing one.
+pub fn open(path: &str) -> Result<RgbImage> {
+    Ok(image::open(path).map_err(|e| image_error(path, e))?.to_rgb8())
+}
+
+pub fn image_error(path: &str, error: ImageError) -> Error {
+    match error {
+        ImageError::IoError(e) => Error::io(path, e),
+        e => Error::io(path, io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+    }
+}
+
+// Lays the image out on a black canvas of the monitor size.
+fn render(img: &RgbImage, width: u32, height: u32, mode: ScaleMode) -> RgbImage {
+    let (iw, ih) = (img.width() as f64, img.height() as f64);
+    let scaled = |scale: f64| {
+        let (w, h) = ((iw * scale).round().max(1.0) as u32, (ih * scale).round().max(1.0) as u32);
+        imageops::resize(img, w, h, FilterType::Triangle)
+    };
+    let fit = (width as f64 / iw).min(height as f64 / ih);
+
+    let img = match mode {
+        ScaleMode::Stretch => return imageops::resize(img, width, height, FilterType::Triangle),
+        ScaleMode::Fill => scaled((width as f64 / iw).max(height as f64 / ih)),
+        ScaleMode::Fit => scaled(fit),
+        ScaleMode::Max if fit < 1.0 => scaled(fit),
+        ScaleMode::Max | ScaleMode::Center | ScaleMode::Tile => img.clone(),
+    };
+
+    let mut canvas = RgbImage::new(width, height);
+    if mode == ScaleMode::Tile {
+        for y in (0..height).step_by(img.height() as usize) {
+            for x in (0..width).step_by(img.width() as usize) {
+                imageops::overlay(&mut canvas, &img, x as i64, y as i64);
+            }
+        }
+    } else {
+        let x = (width as i64 - img.width() as i64) / 2;
+        let y = (height as i64 - img.height() as i64) / 2;
+        imageops::overlay(&mut canvas, &img, x, y);
+    }
+
+    canvas
+}
@@ -0,0 +1,789 @@
+use crate::detect::BACKENDS;
+use crate::error::{Error, Result};
+use crate::function::ScaleMode;
+use crate::monitor::{get_monitors, Monitor};
+use crate::playlist::Order;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, read_to_string};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+// Where downloads come from, tried in a random order.
+pub const SOURCES: [&str; 2] = ["wallhaven", "wallpaperstock"];
+
+// Every layer (config file, profile, environment, command line) only sets what it knows,
+// unset keys fall through to the layer below.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Settings {
+    pub dir: Option<String>,
+    pub interval: Option<i64>,
+    pub resolution: Option<Vec<String>>,
+    pub backend: Option<String>,
+    pub mode: Option<String>,
+    pub order: Option<String>,
+    pub sources: Option<Vec<String>>,
+    pub proxy: Option<String>,
+    pub sfw: Option<bool>,
+    pub restore_on_exit: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct File {
+    #[serde(flatten)]
+    settings: Settings,
+    profile: BTreeMap<String, Profile>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Profile {
+    #[serde(flatten)]
+    settings: Settings,
+    // One entry per connected monitor: its output name, EDID serial or WxH.
+    #[serde(rename = "match")]
+    outputs: Option<Vec<String>>,
+}
+
+impl Settings {
+    // Keys set in `over` win.
+    pub fn merge(self, over: Settings) -> Settings {
+        Settings {
+            dir: over.dir.or(self.dir),
+            interval: over.interval.or(self.interval),
+            resolution: over.resolution.or(self.resolution),
+            backend: over.backend.or(self.backend),
+            mode: over.mode.or(self.mode),
+            order: over.order.or(self.order),
+            sources: over.sources.or(self.sources),
+            proxy: over.proxy.or(self.proxy),
+            sfw: over.sfw.or(self.sfw),
+            restore_on_exit: over.restore_on_exit.or(self.restore_on_exit),
+        }
+    }
+
+    // WALLPAPER_DIR, WALLPAPER_INTERVAL... lists are comma separated.
+    pub fn from_env() -> Result<Settings> {
+        Settings::from_vars(&|key| env::var(key).ok())
+    }
+
+    fn from_vars(env: &dyn Fn(&str) -> Option<String>) -> Result<Settings> {
+        let var = |key: &str| env(&format!("WALLPAPER_{}", key)).filter(|v| !v.is_empty());
+        let list = |key: &str| var(key).map(|v| v.split(',').map(|s| s.trim().to_string()).collect());
+        let flag = |key: &str| -> Result<Option<bool>> {
+            match var(key).as_deref() {
+                None => Ok(None),
+                Some("1") | Some("true") | Some("yes") => Ok(Some(true)),
+                Some("0") | Some("false") | Some("no") => Ok(Some(false)),
+                Some(v) => Err(Error::Config(format!("WALLPAPER_{}: {} is not a boolean", key, v))),
+            }
+        };
+
+        let interval = match var("INTERVAL") {
+            Some(i) => Some(i.parse().map_err(|e| Error::Config(format!("WALLPAPER_INTERVAL: {}", e)))?),
+            None => None,
+        };
+
+        Ok(Settings {
+            dir: var("DIR"),
+            interval,
+            resolution: list("RESOLUTION"),
+            backend: var("BACKEND"),
+            mode: var("MODE"),
+            order: var("ORDER"),
+            sources: list("SOURCES"),
+            proxy: var("PROXY"),
+            sfw: flag("SFW")?,
+            restore_on_exit: flag("RESTORE_ON_EXIT")?,
+        })
+    }
+
+    // $HOME/.wallpaper/ by default, always with a trailing slash.
+    pub fn dir(&self) -> String {
+        let home = env::var("HOME").unwrap_or_default();
+        let mut dir = match &self.dir {
+            Some(d) if d == "~" => home,
+            Some(d) => match d.strip_prefix("~/") {
+                Some(rest) => format!("{}/{}", home, rest),
+                None => d.to_string(),
+            },
+            None => format!("{}/.wallpaper/", home),
+        };
+        if !dir.ends_with('/') {
+            dir.push('/');
+        }
+
+        dir
+    }
+
+    pub fn interval(&self) -> Result<i64> {
+        match self.interval {
+            Some(i) if i <= 0 => Err(Error::Config(format!("interval: {} is not a positive number of seconds", i))),
+            Some(i) => Ok(i),
+            None => Ok(60),
+        }
+    }
+
+    pub fn mode(&self) -> Result<ScaleMode> {
+        let mode = self.mode.as_deref().unwrap_or("fill");
+        ScaleMode::from_name(mode).ok_or_else(|| Error::Config(format!("mode: unknown scale mode {}", mode)))
+    }
+
+    pub fn order(&self) -> Result<Order> {
+        let order = self.order.as_deref().unwrap_or("shuffle");
+        Order::from_name(order).ok_or_else(|| Error::Config(format!("order: unknown order {}", order)))
+    }
+
+    pub fn sources(&self) -> Result<Vec<String>> {
+        let sources = self.sources.clone().unwrap_or_else(|| vec!(SOURCES[0].to_string()));
+        if let Some(s) = sources.iter().find(|s| !SOURCES.contains(&&s[..])) {
+            Err(Error::Config(format!("sources: unknown source {}, known are {}", s, SOURCES.join(", "))))?
+        }
+
+        Ok(sources)
+    }
+}
+
+pub fn path() -> PathBuf {
+    match env::var("XDG_CONFIG_HOME") {
+        Ok(d) if !d.is_empty() => PathBuf::from(d),
+        _ => PathBuf::from(env::var("HOME").unwrap_or_default()).join(".config"),
+    }
+    .join("wallpaper/config.toml")
+}
+
+fn read(path: &Path) -> Result<File> {
+    match read_to_string(path) {
+        Ok(s) => toml::from_str(&s).map_err(|e| Error::Config(format!("{}: {}", path.display(), e))),
+        // A missing file is an empty one.
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(File::default()),
+        Err(e) => Err(Error::io(path.display(), e)),
+    }
+}
+
+// The top level keys, then the profile, then the environment.
+pub fn load(profile: Option<&str>) -> Result<Settings> {
+    load_from(&path(), profile, &|key| env::var(key).ok())
+}
+
+fn load_from(path: &Path, profile: Option<&str>, env: &dyn Fn(&str) -> Option<String>) -> Result<Settings> {
+    let mut file = read(path)?;
+
+    let mut settings = file.settings;
+    if let Some(p) = profile {
+        match file.profile.remove(p) {
+            Some(p) => settings = settings.merge(p.settings),
+            None => Err(Error::Config(format!("{}: no [profile.{}] section", path.display(), p)))?,
+        }
+    }
+
+    Ok(settings.merge(Settings::from_vars(env)?))
+}
+
+// The first profile, by name, whose match pairs off with the monitors one to one.
+pub fn matching(monitors: &[Monitor]) -> Result<Option<String>> {
+    Ok(matching_in(read(&path())?, monitors))
+}
+
+fn matching_in(file: File, monitors: &[Monitor]) -> Option<String> {
+    file.profile
+        .into_iter()
+        .find(|(_, p)| p.outputs.as_ref().map(|o| fits(o, monitors)).unwrap_or(false))
+        .map(|(name, _)| name)
+}
+
+fn fits(outputs: &[String], monitors: &[Monitor]) -> bool {
+    !outputs.is_empty() && outputs.len() == monitors.len() && pair(outputs, monitors, &mut vec![false; monitors.len()])
+}
+
+// A later entry may only fit the monitor an earlier one took, so every pairing is tried.
+fn pair(outputs: &[String], monitors: &[Monitor], taken: &mut Vec<bool>) -> bool {
+    let (output, rest) = match outputs.split_first() {
+        Some(o) => o,
+        None => return true,
+    };
+
+    for (i, m) in monitors.iter().enumerate() {
+        let fits = *output == m.name || (!m.serial.is_empty() && *output == m.serial) || *output == m.resolution();
+        if fits && !taken[i] {
+            taken[i] = true;
+            if pair(rest, monitors, taken) {
+                return true;
+            }
+            taken[i] = false;
+        }
+    }
+
+    false
+}
+
+// Everything the rotation and the downloads need, see Config::builder.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub(crate) dir: String,
+    pub(crate) is_video: bool,
+    pub(crate) is_gif: bool,
+    // 0 plays a gif at its own delays and a video at its own rate.
+    pub(crate) fps: u16,
+    pub(crate) file: Option<String>,
+    // How fast a video or gif plays, 2.0 twice as fast.
+    pub(crate) speed: f64,
+    // Loops played before the last frame stays, 0 plays forever.
+    pub(crate) loop_count: u32,
+    pub(crate) resolution: Option<Vec<String>>,
+    pub(crate) download_sfw: bool,
+    pub(crate) interval: i64,
+    pub(crate) proxy: Option<String>,
+    pub(crate) backend: Option<String>,
+    pub(crate) mode: ScaleMode,
+    pub(crate) monitors: Vec<Monitor>,
+    pub(crate) order: Order,
+    pub(crate) restore_on_exit: bool,
+    pub(crate) sources: Vec<String>,
+    // The config file, None when it isn't read at all.
+    pub(crate) config_file: Option<PathBuf>,
+    // Pinned with --profile or WALLPAPER_PROFILE.
+    pub(crate) profile: Option<String>,
+    // The profile in use, the pinned one or the one matching the monitors.
+    pub(crate) active_profile: Option<String>,
+    // Set through the builder, they still win when the config file is read again.
+    pub(crate) overrides: Settings,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ConfigBuilder {
+    settings: Settings,
+    config_file: bool,
+    config_path: Option<PathBuf>,
+    profile: Option<String>,
+    monitors: Option<Vec<Monitor>>,
+    video: Option<String>,
+    gif: Option<(String, u16)>,
+    speed: Option<f64>,
+    loop_count: Option<u32>,
+}
+
+impl ConfigBuilder {
+    pub fn dir(mut self, dir: &str) -> ConfigBuilder {
+        self.settings.dir = Some(dir.to_string());
+        self
+    }
+
+    pub fn interval(mut self, secs: i64) -> ConfigBuilder {
+        self.settings.interval = Some(secs);
+        self
+    }
+
+    // Wins over the resolutions of the detected monitors.
+    pub fn resolution(mut self, resolution: Vec<String>) -> ConfigBuilder {
+        self.settings.resolution = Some(resolution);
+        self
+    }
+
+    pub fn backend(mut self, backend: &str) -> ConfigBuilder {
+        self.settings.backend = Some(backend.to_string());
+        self
+    }
+
+    pub fn mode(mut self, mode: ScaleMode) -> ConfigBuilder {
+        self.settings.mode = Some(mode.name().to_string());
+        self
+    }
+
+    pub fn order(mut self, order: Order) -> ConfigBuilder {
+        self.settings.order = Some(order.name().to_string());
+        self
+    }
+
+    pub fn sources(mut self, sources: Vec<String>) -> ConfigBuilder {
+        self.settings.sources = Some(sources);
+        self
+    }
+
+    pub fn proxy(mut self, proxy: &str) -> ConfigBuilder {
+        self.settings.proxy = Some(proxy.to_string());
+        self
+    }
+
+    pub fn sfw(mut self, sfw: bool) -> ConfigBuilder {
+        self.settings.sfw = Some(sfw);
+        self
+    }
+
+    pub fn restore_on_exit(mut self, restore: bool) -> ConfigBuilder {
+        self.settings.restore_on_exit = Some(restore);
+        self
+    }
+
+    // Keys set in `settings` win over the ones set before.
+    pub fn settings(mut self, settings: Settings) -> ConfigBuilder {
+        self.settings = self.settings.merge(settings);
+        self
+    }
+
+    // Reads the config file and the environment underneath the settings given here.
+    // Without a profile the one matching the monitors is used.
+    pub fn config_file(mut self, profile: Option<&str>) -> ConfigBuilder {
+        self.config_file = true;
+        self.profile = profile.map(|p| p.to_string());
+        self
+    }
+
+    // Where config_file reads from, config::path() when not given.
+    pub fn config_path(mut self, path: &Path) -> ConfigBuilder {
+        self.config_path = Some(path.to_path_buf());
+        self
+    }
+
+    // Detected when not given.
+    pub fn monitors(mut self, monitors: Vec<Monitor>) -> ConfigBuilder {
+        self.monitors = Some(monitors);
+        self
+    }
+
+    // Plays a video instead of rotating.
+    pub fn video(mut self, file: &str) -> ConfigBuilder {
+        self.video = Some(file.to_string());
+        self.gif = None;
+        self
+    }
+
+    // 2.0 plays videos and gifs twice as fast.
+    pub fn speed(mut self, speed: f64) -> ConfigBuilder {
+        self.speed = Some(speed);
+        self
+    }
+
+    // Stops a video or gif on its last frame after `count` loops, 0 plays forever.
+    pub fn loop_count(mut self, count: u32) -> ConfigBuilder {
+        self.loop_count = Some(count);
+        self
+    }
+
+    // Plays a gif at `fps` instead of rotating, 0 keeps the delays of its frames.
+    pub fn gif(mut self, file: &str, fps: u16) -> ConfigBuilder {
+        self.gif = Some((file.to_string(), fps));
+        self.video = None;
+        self
+    }
+
+    // Validates the settings and creates the wallpaper directory when it is missing.
+    pub fn build(self) -> Result<Config> {
+        let monitors = match self.monitors {
+            Some(m) => m,
+            None => get_monitors().unwrap_or_default(),
+        };
+        let config_file = if self.config_file { Some(self.config_path.unwrap_or_else(path)) } else { None };
+        let (active_profile, settings) = from_file(&config_file, &self.profile, &monitors)?;
+        let settings = settings.merge(self.settings.clone());
+
+        if let Some(b) = settings.backend.as_ref().filter(|b| !BACKENDS.contains(&&b[..])) {
+            Err(Error::Config(format!("backend: unknown backend {}, known are {}", b, BACKENDS.join(", "))))?
+        }
+        if let Some(s) = self.speed.filter(|s| !s.is_finite() || *s <= 0.0) {
+            Err(Error::Config(format!("speed: {} is not a positive number", s)))?
+        }
+        let dir = settings.dir();
+        create_dir(&dir)?;
+
+        Ok(Config {
+            interval: settings.interval()?,
+            mode: settings.mode()?,
+            order: settings.order()?,
+            sources: settings.sources()?,
+            dir,
+            is_video: self.video.is_some(),
+            is_gif: self.gif.is_some(),
+            fps: self.gif.as_ref().map(|g| g.1).unwrap_or(0),
+            file: self.video.or(self.gif.map(|g| g.0)),
+            speed: self.speed.unwrap_or(1.0),
+            loop_count: self.loop_count.unwrap_or(0),
+            resolution: settings.resolution,
+            download_sfw: settings.sfw.unwrap_or(false),
+            proxy: settings.proxy,
+            backend: settings.backend,
+            monitors,
+            restore_on_exit: settings.restore_on_exit.unwrap_or(false),
+            config_file,
+            profile: self.profile,
+            active_profile,
+            overrides: self.settings,
+        })
+    }
+}
+
+fn create_dir(dir: &str) -> Result<()> {
+    let path = Path::new(dir);
+    let metadata = match path.metadata() {
+        Ok(m) => m,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            fs::create_dir_all(path).map_err(|e| Error::io(dir, e))?;
+            path.metadata().map_err(|e| Error::io(dir, e))?
+        }
+        Err(e) => Err(Error::io(dir, e))?,
+    };
+    if !metadata.is_dir() {
+        Err(Error::Config(format!("{} is not a dir", dir)))?
+    }
+
+    Ok(())
+}
+
+// The profile in use and the settings of the config file with it, nothing without a file.
+// A pinned profile wins over the profiles matching the monitors.
+fn from_file(file: &Option<PathBuf>, pinned: &Option<String>, monitors: &[Monitor]) -> Result<(Option<String>, Settings)> {
+    let file = match file {
+        Some(f) => f,
+        None => return Ok((None, Settings::default())),
+    };
+    let active = match pinned {
+        Some(p) => Some(p.clone()),
+        None => matching_in(read(file)?, monitors),
+    };
+    let settings = load_from(file, active.as_deref(), &|key| env::var(key).ok())?;
+
+    Ok((active, settings))
+}
+
+impl Config {
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    // The resolved settings, in the format of the config file.
+    pub fn settings(&self) -> Settings {
+        Settings {
+            dir: Some(self.dir.clone()),
+            interval: Some(self.interval),
+            resolution: self.resolution.clone(),
+            backend: self.backend.clone(),
+            mode: Some(self.mode.name().to_string()),
+            order: Some(self.order.name().to_string()),
+            sources: Some(self.sources.clone()),
+            proxy: self.proxy.clone(),
+            sfw: Some(self.download_sfw),
+            restore_on_exit: Some(self.restore_on_exit),
+        }
+    }
+
+    pub fn dir(&self) -> &str {
+        &self.dir
+    }
+
+    pub fn backend(&self) -> &Option<String> {
+        &self.backend
+    }
+
+    pub fn mode(&self) -> ScaleMode {
+        self.mode
+    }
+
+    pub fn order(&self) -> Order {
+        self.order
+    }
+
+    pub fn monitors(&self) -> &[Monitor] {
+        &self.monitors
+    }
+
+    pub fn is_video(&self) -> bool {
+        self.is_video
+    }
+
+    pub fn is_gif(&self) -> bool {
+        self.is_gif
+    }
+
+    // The profile in use and whether it was picked from the monitors rather than pinned.
+    pub fn profile(&self) -> Option<(&str, bool)> {
+        self.active_profile.as_deref().map(|p| (p, self.profile.is_none()))
+    }
+
+    // Resolutions given with --resolution win over the detected monitors.
+    pub fn resolutions(&self) -> Vec<String> {
+        match &self.resolution {
+            Some(r) => r.clone(),
+            None => self.monitors.iter().map(|m| m.resolution()).collect(),
+        }
+    }
+
+    // Changes made while running, e.g. through the control socket, win over the config file
+    // like the builder settings do, so a reload keeps them.
+    pub fn set_interval(&mut self, secs: i64) {
+        self.interval = secs;
+        self.overrides.interval = Some(secs);
+    }
+
+    pub fn set_dir(&mut self, dir: &str) {
+        self.overrides.dir = Some(dir.to_string());
+        self.dir = self.overrides.dir();
+    }
+
+    // Reads the config file again, the settings given to the builder still win.
+    // Without a pinned profile the one matching the current monitors is picked again.
+    pub fn reload(&mut self) -> Result<()> {
+        let (active, settings) = from_file(&self.config_file, &self.profile, &self.monitors)?;
+        let settings = settings.merge(self.overrides.clone());
+        let (interval, mode, order, sources) = (settings.interval()?, settings.mode()?, settings.order()?, settings.sources()?);
+
+        self.dir = settings.dir();
+        self.interval = interval;
+        self.resolution = settings.resolution;
+        self.mode = mode;
+        self.order = order;
+        self.sources = sources;
+        self.proxy = settings.proxy;
+        self.download_sfw = settings.sfw.unwrap_or(false);
+        self.active_profile = active;
+
+        Ok(())
+    }
+
+    // Returns whether the monitor layout changed since the last detection.
+    pub fn refresh_monitors(&mut self) -> bool {
+        match get_monitors() {
+            Ok(m) if m != self.monitors => {
+                self.monitors = m;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Follows the profile matching the monitors, unless one is pinned.
+    // Returns whether another profile is in use now.
+    pub fn switch_profile(&mut self) -> Result<bool> {
+        let file = match &self.config_file {
+            Some(f) if self.profile.is_none() => f,
+            _ => return Ok(false),
+        };
+        if matching_in(read(file)?, &self.monitors) == self.active_profile {
+            return Ok(false);
+        }
+        self.reload()?;
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::gen_rand_string;
+    use std::collections::HashMap;
+    use crate::monitor::tests::{LIST_ACTIVE_MONITORS, SWAY_OUTPUTS};
+    use crate::monitor::{parse_active_monitors, parse_sway_outputs};
+
+    // eDP-1 1920x1080 without a serial and DP-2 1440x2560 with GH85D6CS1AHL.
+    fn sway() -> Vec<Monitor> {
+        parse_sway_outputs(SWAY_OUTPUTS).unwrap()
+    }
+
+    // eDP-1 1920x1080, HDMI-1 1080x1920 and DP-1 2560x1440, none with a serial.
+    fn xrandr() -> Vec<Monitor> {
+        parse_active_monitors(LIST_ACTIVE_MONITORS)
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("wallpaper-test-{}", gen_rand_string()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn the_command_line_wins_over_the_environment_over_the_profile_over_the_file() {
+        let dir = temp_dir();
+        let file = dir.join("config.toml");
+        fs::write(
+            &file,
+            r#"
+            dir = "/file"
+            interval = 10
+            mode = "fit"
+            order = "name"
+
+            [profile.docked]
+            interval = 20
+            mode = "center"
+            order = "mtime"
+            "#,
+        )
+        .unwrap();
+        let env: HashMap<&str, &str> = vec!(("WALLPAPER_MODE", "stretch"), ("WALLPAPER_ORDER", "shuffle"), ("WALLPAPER_DIR", ""))
+            .into_iter()
+            .collect();
+        let cli = Settings {
+            order: Some("name".to_string()),
+            ..Settings::default()
+        };
+
+        let settings = load_from(&file, Some("docked"), &|k| env.get(k).map(|v| v.to_string())).unwrap().merge(cli);
+
+        // An empty variable is an unset one.
+        assert_eq!(settings.dir.as_deref(), Some("/file"));
+        assert_eq!(settings.interval, Some(20));
+        assert_eq!(settings.mode.as_deref(), Some("stretch"));
+        assert_eq!(settings.order.as_deref(), Some("name"));
+        assert_eq!(settings.sfw, None);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn bad_environment_values_are_config_errors() {
+        let cases = vec!(
+            ("WALLPAPER_INTERVAL", "soon"),
+            ("WALLPAPER_INTERVAL", "1.5"),
+            ("WALLPAPER_SFW", "maybe"),
+            ("WALLPAPER_RESTORE_ON_EXIT", "2"),
+        );
+
+        for (key, value) in cases {
+            let e = Settings::from_vars(&|k| if k == key { Some(value.to_string()) } else { None }).unwrap_err();
+            assert!(matches!(&e, Error::Config(m) if m.starts_with(key)), "{}: {:?}", key, e);
+        }
+        let env = |k: &str| match k {
+            "WALLPAPER_SFW" => Some("yes".to_string()),
+            "WALLPAPER_SOURCES" => Some("wallhaven, wallpaperstock".to_string()),
+            _ => None,
+        };
+        let settings = Settings::from_vars(&env).unwrap();
+        assert_eq!(settings.sfw, Some(true));
+        assert_eq!(settings.sources, Some(vec!("wallhaven".to_string(), "wallpaperstock".to_string())));
+    }
+
+    #[test]
+    fn the_dir_expands_the_home() {
+        let home = env::var("HOME").unwrap_or_default();
+        let dir = |d: Option<&str>| {
+            Settings {
+                dir: d.map(|d| d.to_string()),
+                ..Settings::default()
+            }
+            .dir()
+        };
+
+        assert_eq!(dir(Some("~/walls")), format!("{}/walls/", home));
+        assert_eq!(dir(Some("~")), format!("{}/", home));
+        assert_eq!(dir(None), format!("{}/.wallpaper/", home));
+        assert_eq!(dir(Some("/srv/~/walls/")), "/srv/~/walls/");
+        assert_eq!(dir(Some("~walls")), "~walls/");
+    }
+
+    #[test]
+    fn outputs_fit_monitors() {
+        let mut twins = sway();
+        twins.push(Monitor::new("HDMI-A-1", 1920, 1080, 3360, 0));
+
+        let cases: Vec<(&[&str], Vec<Monitor>, bool)> = vec!(
+            (&["eDP-1", "DP-2"], sway(), true),
+            (&["DP-2", "eDP-1"], sway(), true),
+            (&["GH85D6CS1AHL", "eDP-1"], sway(), true),
+            (&["1440x2560", "1920x1080"], sway(), true),
+            (&["1080x1920", "DP-1", "eDP-1"], xrandr(), true),
+            // A monitor made with --setmonitor goes by its output.
+            (&["1080x1920", "SIDE", "eDP-1"], xrandr(), false),
+            // Taken by WxH first, eDP-1 only fits once 1920x1080 moves over to HDMI-A-1.
+            (&["1920x1080", "eDP-1", "DP-2"], twins.clone(), true),
+            (&["1920x1080", "1920x1080", "eDP-1"], twins, false),
+            // An unknown serial never matches an empty entry.
+            (&["", "DP-2"], sway(), false),
+            (&["DP-1", "eDP-1"], sway(), false),
+            (&["eDP-1", "eDP-1"], sway(), false),
+            (&["eDP-1"], sway(), false),
+            (&["eDP-1", "DP-2", "HDMI-1"], sway(), false),
+            (&[], vec!(), false),
+        );
+
+        for (outputs, monitors, fit) in cases {
+            let outputs = outputs.iter().map(|o| o.to_string()).collect::<Vec<String>>();
+            assert_eq!(fits(&outputs, &monitors), fit, "{:?}", outputs);
+        }
+    }
+
+    #[test]
+    fn the_first_matching_profile_wins() {
+        let file: File = toml::from_str(
+            r#"
+            [profile.laptop]
+            match = ["eDP-1"]
+
+            [profile.docked]
+            match = ["eDP-1", "GH85D6CS1AHL"]
+
+            [profile.desk]
+            match = ["1920x1080", "DP-2"]
+
+            [profile.unmatched]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(matching_in(file, &sway()), Some("desk".to_string()));
+        let file: File = toml::from_str("[profile.laptop]\nmatch = [\"eDP-1\"]").unwrap();
+        assert_eq!(matching_in(file, &xrandr()), None);
+        assert_eq!(matching_in(File::default(), &sway()), None);
+    }
+
+    #[test]
+    fn runtime_changes_survive_a_profile_switch() {
+        let dir = temp_dir();
+        let file = dir.join("config.toml");
+        fs::write(
+            &file,
+            r#"
+            interval = 30
+            mode = "fit"
+
+            [profile.laptop]
+            match = ["eDP-1"]
+
+            [profile.docked]
+            match = ["eDP-1", "DP-2"]
+            interval = 600
+            mode = "center"
+            "#,
+        )
+        .unwrap();
+        let mut config = Config::builder()
+            .dir(&dir.display().to_string())
+            .config_file(None)
+            .config_path(&file)
+            .monitors(sway().into_iter().take(1).collect())
+            .build()
+            .unwrap();
+        assert_eq!(config.profile(), Some(("laptop", true)));
+        assert_eq!((config.interval, config.mode), (30, ScaleMode::Fit));
+
+        config.set_interval(50);
+        config.monitors = sway();
+
+        assert!(config.switch_profile().unwrap());
+        assert_eq!(config.profile(), Some(("docked", true)));
+        assert_eq!((config.interval, config.mode), (50, ScaleMode::Center));
+        assert!(!config.switch_profile().unwrap());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn runtime_changes_survive_a_reload() {
+        let dir = env::temp_dir().join(format!("wallpaper-test-{}", gen_rand_string()));
+        let other = dir.join("other");
+        let mut config = Config::builder()
+            .dir(&dir.display().to_string())
+            .interval(100)
+            .monitors(vec!())
+            .build()
+            .unwrap();
+
+        config.set_interval(50);
+        config.set_dir(&other.display().to_string());
+        config.reload().unwrap();
+
+        assert_eq!(config.interval, 50);
+        assert_eq!(config.dir(), format!("{}/", other.display()));
+        let _ = fs::remove_dir_all(dir);
+    }
+}
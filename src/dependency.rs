@@ -0,0 +1,257 @@
+use crate::error::{Error, Result};
+use crate::runner::CommandRunner;
+use regex::Regex;
+use std::env;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+// A program we need. It is looked up on PATH without being run, only a minimum version
+// makes us ask it for its version.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dependency {
+    pub name: &'static str,
+    // Tried in order, the first one on PATH is used.
+    pub programs: &'static [&'static str],
+    pub minimum: Option<&'static str>,
+    // What makes the program print its version.
+    pub version_flag: &'static str,
+    pub hint: &'static str,
+}
+
+pub const XRANDR: Dependency = Dependency {
+    name: "xrandr",
+    programs: &["xrandr"],
+    // --listactivemonitors came with 1.5.0.
+    minimum: Some("1.5.0"),
+    version_flag: "--version",
+    hint: "apt install x11-xserver-utils, pacman -S xorg-xrandr, dnf install xrandr",
+};
+
+pub const GSETTINGS: Dependency = Dependency {
+    name: "gsettings",
+    programs: &["gsettings"],
+    minimum: None,
+    version_flag: "--version",
+    hint: "apt install libglib2.0-bin, pacman -S glib2, dnf install glib2",
+};
+
+// KDE takes qdbus as well, Plasma 6 names it qdbus6.
+pub const DBUS_SEND: Dependency = Dependency {
+    name: "dbus-send",
+    programs: &["dbus-send", "qdbus6", "qdbus"],
+    minimum: None,
+    version_flag: "--version",
+    hint: "apt install dbus, pacman -S dbus, dnf install dbus-tools",
+};
+
+pub const XFCONF_QUERY: Dependency = Dependency {
+    name: "xfconf-query",
+    programs: &["xfconf-query"],
+    minimum: None,
+    version_flag: "--version",
+    hint: "apt install xfconf, pacman -S xfconf, dnf install xfconf",
+};
+
+pub const SWAYBG: Dependency = Dependency {
+    name: "swaybg",
+    programs: &["swaybg"],
+    minimum: None,
+    version_flag: "--version",
+    hint: "apt install swaybg, pacman -S swaybg, dnf install swaybg",
+};
+
+pub const FFMPEG: Dependency = Dependency {
+    name: "ffmpeg",
+    programs: &["ffmpeg"],
+    minimum: Some("4.2.3"),
+    version_flag: "-version",
+    hint: "apt install ffmpeg, pacman -S ffmpeg, dnf install ffmpeg",
+};
+
+// Comes with ffmpeg, reads the frame rate of videos.
+pub const FFPROBE: Dependency = Dependency {
+    name: "ffprobe",
+    programs: &["ffprobe"],
+    minimum: None,
+    version_flag: "-version",
+    hint: "apt install ffmpeg, pacman -S ffmpeg, dnf install ffmpeg",
+};
+
+// ImageMagick 7 installs magick, 6 only convert.
+pub const IMAGEMAGICK: Dependency = Dependency {
+    name: "ImageMagick",
+    programs: &["magick", "convert"],
+    minimum: None,
+    version_flag: "-version",
+    hint: "apt install imagemagick, pacman -S imagemagick, dnf install ImageMagick",
+};
+
+impl Dependency {
+    // The first of the programs found on PATH.
+    pub fn program(&self) -> Option<&'static str> {
+        self.programs.iter().copied().find(|p| find_program(p).is_some())
+    }
+
+    // Where the program is, or why it can't be used.
+    pub fn probe(&self, runner: &dyn CommandRunner) -> Result<PathBuf> {
+        let (program, path) = match self.programs.iter().find_map(|p| find_program(p).map(|path| (*p, path))) {
+            Some(found) => found,
+            None => Err(Error::dependency(self.name, format!("not found on PATH ({})", self.hint)))?,
+        };
+
+        if let Some(minimum) = self.minimum {
+            // A version we can't read doesn't keep the program from being used.
+            let output = runner.run(program, &[self.version_flag]).unwrap_or_default();
+            if let Some(found) = parse_version(&output) {
+                if found < parse_version(minimum).unwrap_or_default() {
+                    Err(Error::dependency(
+                        self.name,
+                        format!("{} is older than {} ({})", version_string(&found), minimum, self.hint),
+                    ))?
+                }
+            }
+        }
+
+        Ok(path)
+    }
+}
+
+// Every dependency that can't be used, in a single error.
+pub fn check(dependencies: &[Dependency], runner: &dyn CommandRunner) -> Result<()> {
+    let mut names = vec!();
+    let mut problems = vec!();
+    for d in dependencies.iter() {
+        if names.contains(&d.name) {
+            continue;
+        }
+        if let Err(e) = d.probe(runner) {
+            names.push(d.name);
+            problems.push(format!("  {}", e));
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    Err(Error::dependency(
+        &names.join(", "),
+        format!("please install first:\n{}", problems.join("\n")),
+    ))
+}
+
+// Like the shell does: a name with a slash is taken as it is, others are searched in PATH.
+pub fn find_program(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        return Some(PathBuf::from(program)).filter(|p| is_executable(p));
+    }
+
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|p| is_executable(p))
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+// The first version number in the output, ImageMagick's 7.0.10-16 reads as 7.0.10.16.
+pub fn parse_version(output: &str) -> Option<Vec<u32>> {
+    let re = Regex::new(r"(\d+(?:\.\d+)+)(?:-(\d+))?").ok()?;
+    let caps = re.captures(output)?;
+    let mut version = caps[1].split('.').filter_map(|n| n.parse().ok()).collect::<Vec<u32>>();
+    if let Some(patch) = caps.get(2).and_then(|p| p.as_str().parse().ok()) {
+        version.push(patch);
+    }
+
+    Some(version)
+}
+
+fn version_string(version: &[u32]) -> String {
+    version.iter().map(|n| n.to_string()).collect::<Vec<String>>().join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::gen_rand_string;
+    use crate::runner::FakeRunner;
+    use std::fs;
+
+    // sh is on every PATH, what it prints is up to the FakeRunner.
+    const SH: Dependency = Dependency {
+        name: "sh",
+        programs: &["wallpaper-no-such-program", "sh"],
+        minimum: Some("4.2.3"),
+        version_flag: "-version",
+        hint: "apt install dash",
+    };
+
+    const MISSING: Dependency = Dependency {
+        name: "missing",
+        programs: &["wallpaper-no-such-program"],
+        minimum: None,
+        version_flag: "--version",
+        hint: "apt install missing",
+    };
+
+    #[test]
+    fn versions() {
+        let cases = vec!(
+            ("Version: ImageMagick 7.0.10-16 Q16 x86_64 2020-05-30", Some(vec!(7, 0, 10, 16))),
+            ("ffmpeg version n6.0 Copyright (c) 2000-2023 the FFmpeg developers", Some(vec!(6, 0))),
+            ("ffmpeg version 4.2.3-0ubuntu1 Copyright (c) 2000-2020", Some(vec!(4, 2, 3, 0))),
+            ("xrandr program version       1.5.1\nServer reports RandR version 1.6", Some(vec!(1, 5, 1))),
+            ("ffmpeg version N-110000-g0123456789", None),
+            ("", None),
+        );
+
+        for (output, version) in cases {
+            assert_eq!(parse_version(output), version, "{}", output);
+        }
+        assert!(parse_version("4.2.3-0ubuntu1").unwrap() >= parse_version("4.2.3").unwrap());
+        assert!(parse_version("n6.0").unwrap() >= parse_version("4.2.3").unwrap());
+        assert!(parse_version("4.2.2-1").unwrap() < parse_version("4.2.3").unwrap());
+    }
+
+    #[test]
+    fn programs_are_found_when_executable() {
+        let dir = std::env::temp_dir().join(format!("wallpaper-test-{}", gen_rand_string()));
+        fs::create_dir_all(&dir).unwrap();
+        let (script, text) = (dir.join("script"), dir.join("text"));
+        fs::write(&script, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(&text, "").unwrap();
+
+        assert_eq!(find_program(&script.display().to_string()), Some(script));
+        assert_eq!(find_program(&text.display().to_string()), None);
+        assert_eq!(find_program(&dir.display().to_string()), None);
+        assert!(find_program("sh").is_some());
+        assert_eq!(find_program("wallpaper-no-such-program"), None);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn old_and_missing_programs_fail_the_check_together() {
+        let runner = FakeRunner::new();
+        runner.reply("sh", "ffmpeg version n6.0 Copyright (c) 2000-2023 the FFmpeg developers");
+        assert_eq!(SH.program(), Some("sh"));
+        assert!(check(&[SH], &runner).is_ok());
+        assert_eq!(runner.calls(), vec!(vec!("sh".to_string(), "-version".to_string())));
+
+        // A version we can't read is let through.
+        runner.reply("sh", "sh: -version: invalid option");
+        assert!(check(&[SH], &runner).is_ok());
+
+        runner.reply("sh", "ffmpeg version 4.2.2-1 Copyright (c) 2000-2019");
+        let e = check(&[SH, MISSING, SH], &runner).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "sh, missing: please install first:\n  \
+             sh: 4.2.2.1 is older than 4.2.3 (apt install dash)\n  \
+             missing: not found on PATH (apt install missing)"
+        );
+    }
+}
@@ -0,0 +1,223 @@
+use crate::function::Hyprpaper;
+use std::env;
+use std::fs::read_dir;
+
+pub const BACKENDS: [&str; 6] = ["wm", "gnome", "kde", "xfce", "wlroots", "hyprpaper"];
+
+// Daemons that own the desktop background, as they show up in /proc/<pid>/comm.
+const DAEMONS: [(&str, Backend); 5] = [
+    ("gnome-shell", Backend::Gnome),
+    ("plasmashell", Backend::Kde),
+    ("xfdesktop", Backend::Xfce),
+    ("hyprpaper", Backend::Hyprpaper),
+    ("swaybg", Backend::Wlroots),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Wm,
+    Gnome,
+    Kde,
+    Xfce,
+    Wlroots,
+    Hyprpaper,
+}
+
+impl Backend {
+    pub fn from_name(name: &str) -> Option<Backend> {
+        match &name.to_lowercase()[..] {
+            "wm" | "feh" => Some(Backend::Wm),
+            "gnome" => Some(Backend::Gnome),
+            "kde" | "plasma" => Some(Backend::Kde),
+            "xfce" => Some(Backend::Xfce),
+            "wlroots" | "swaybg" => Some(Backend::Wlroots),
+            "hyprpaper" => Some(Backend::Hyprpaper),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Wm => "wm",
+            Backend::Gnome => "gnome",
+            Backend::Kde => "kde",
+            Backend::Xfce => "xfce",
+            Backend::Wlroots => "wlroots",
+            Backend::Hyprpaper => "hyprpaper",
+        }
+    }
+
+    // Names used by XDG_CURRENT_DESKTOP entries and DESKTOP_SESSION values.
+    fn from_desktop(desktop: &str) -> Option<Backend> {
+        let desktop = desktop.to_lowercase();
+        match &desktop[..] {
+            "gnome" | "gnome-classic" | "gnome-flashback" | "unity" => Some(Backend::Gnome),
+            "kde" | "plasma" | "plasmawayland" => Some(Backend::Kde),
+            "xfce" | "xfce4" => Some(Backend::Xfce),
+            "hyprland" => Some(Backend::Hyprpaper),
+            "sway" | "river" | "wlroots" => Some(Backend::Wlroots),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Detection {
+    pub backend: Backend,
+    pub reason: String,
+    pub desktops: Vec<String>,
+    pub session: Option<String>,
+    pub wayland: Option<String>,
+    pub daemons: Vec<String>,
+}
+
+pub fn detect(forced: &Option<String>) -> Detection {
+    detect_from(forced, &|key| env::var(key).ok(), &running_processes())
+}
+
+// Detects from the environment looked up through `env` and the names of the running processes.
+pub fn detect_from(forced: &Option<String>, env: &dyn Fn(&str) -> Option<String>, processes: &[String]) -> Detection {
+    let desktops = env("XDG_CURRENT_DESKTOP")
+        .unwrap_or_default()
+        .split(':')
+        .filter(|d| !d.is_empty())
+        .map(|d| d.to_string())
+        .collect::<Vec<String>>();
+    let session = env("DESKTOP_SESSION").filter(|s| !s.is_empty());
+    let wayland = env("WAYLAND_DISPLAY").filter(|s| !s.is_empty());
+    let mut daemons: Vec<String> = vec!();
+    for p in processes {
+        if DAEMONS.iter().any(|(d, _)| d == p) && !daemons.contains(p) {
+            daemons.push(p.to_string());
+        }
+    }
+
+    let mut detection = Detection {
+        backend: Backend::Wm,
+        reason: "no desktop environment found, falling back to the window manager".to_string(),
+        desktops,
+        session,
+        wayland,
+        daemons,
+    };
+
+    if let Some(b) = forced.as_ref().and_then(|f| Backend::from_name(f)) {
+        detection.backend = b;
+        detection.reason = "forced by --backend".to_string();
+        return detection;
+    }
+
+    let from_desktops = detection
+        .desktops
+        .iter()
+        .find_map(|d| Backend::from_desktop(d).map(|b| (b, format!("XDG_CURRENT_DESKTOP contains {}", d))));
+    // Sessions are often named after their variant (gnome-xorg, plasmawayland, ubuntu-wayland...).
+    let from_session = detection.session.as_ref().and_then(|s| {
+        s.split(['-', '/'])
+            .find_map(Backend::from_desktop)
+            .map(|b| (b, format!("DESKTOP_SESSION is {}", s)))
+    });
+    let from_daemons = DAEMONS
+        .iter()
+        .find(|(d, _)| detection.daemons.iter().any(|r| r == d))
+        .map(|(d, b)| (*b, format!("{} is running", d)));
+    let from_wayland = detection
+        .wayland
+        .as_ref()
+        .map(|w| (Backend::Wlroots, format!("WAYLAND_DISPLAY is {}", w)));
+
+    if let Some((backend, reason)) = from_desktops.or(from_session).or(from_daemons).or(from_wayland) {
+        detection.backend = backend;
+        detection.reason = reason;
+    }
+
+    // Hyprland without hyprpaper is still a wlroots compositor swaybg can draw on.
+    if detection.backend == Backend::Hyprpaper && Hyprpaper::detect_with(env).is_none() {
+        detection.backend = Backend::Wlroots;
+        detection.reason = format!("{}, but the hyprpaper socket is missing", detection.reason);
+    }
+
+    detection
+}
+
+// As they show up in /proc/<pid>/comm.
+fn running_processes() -> Vec<String> {
+    let entries = match read_dir("/proc") {
+        Ok(r) => r,
+        Err(_e) => return vec!(),
+    };
+
+    entries
+        .flatten()
+        .filter_map(|entry| std::fs::read_to_string(entry.path().join("comm")).ok())
+        .map(|comm| comm.trim().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect_in(vars: &[(&str, &str)], processes: &[&str]) -> Detection {
+        let env = |key: &str| vars.iter().find(|(k, _)| *k == key).map(|(_, v)| v.to_string());
+        let processes = processes.iter().map(|p| p.to_string()).collect::<Vec<String>>();
+
+        detect_from(&None, &env, &processes)
+    }
+
+    #[test]
+    fn desktops_and_sessions() {
+        let cases: Vec<(&[(&str, &str)], Backend)> = vec!(
+            (&[("XDG_CURRENT_DESKTOP", "ubuntu:GNOME")], Backend::Gnome),
+            (&[("XDG_CURRENT_DESKTOP", "pop:GNOME")], Backend::Gnome),
+            (&[("XDG_CURRENT_DESKTOP", "GNOME-Classic:GNOME")], Backend::Gnome),
+            (&[("XDG_CURRENT_DESKTOP", "KDE"), ("WAYLAND_DISPLAY", "wayland-0")], Backend::Kde),
+            (&[("XDG_CURRENT_DESKTOP", "XFCE")], Backend::Xfce),
+            (&[("DESKTOP_SESSION", "plasmawayland")], Backend::Kde),
+            (&[("DESKTOP_SESSION", "gnome-xorg")], Backend::Gnome),
+            (&[("DESKTOP_SESSION", "/usr/share/xsessions/xfce")], Backend::Xfce),
+            (&[("XDG_CURRENT_DESKTOP", "sway"), ("DESKTOP_SESSION", "gnome")], Backend::Wlroots),
+            (&[("WAYLAND_DISPLAY", "wayland-1")], Backend::Wlroots),
+            (&[("XDG_CURRENT_DESKTOP", ""), ("DESKTOP_SESSION", "")], Backend::Wm),
+            (&[], Backend::Wm),
+        );
+
+        for (vars, backend) in cases {
+            assert_eq!(detect_in(vars, &[]).backend, backend, "{:?}", vars);
+        }
+    }
+
+    #[test]
+    fn hyprland_without_its_socket_falls_back_to_wlroots() {
+        let vars = [
+            ("XDG_CURRENT_DESKTOP", "Hyprland"),
+            ("HYPRLAND_INSTANCE_SIGNATURE", "wallpaper-test-no-such-instance"),
+            ("XDG_RUNTIME_DIR", "/nonexistent"),
+        ];
+
+        let detection = detect_in(&vars, &[]);
+
+        assert_eq!(detection.backend, Backend::Wlroots);
+        assert_eq!(detection.reason, "XDG_CURRENT_DESKTOP contains Hyprland, but the hyprpaper socket is missing");
+    }
+
+    #[test]
+    fn running_daemons_are_the_fallback() {
+        let detection = detect_in(&[("WAYLAND_DISPLAY", "wayland-0")], &["systemd", "xfdesktop", "swaybg", "xfdesktop"]);
+        assert_eq!(detection.backend, Backend::Xfce);
+        assert_eq!(detection.daemons, vec!("xfdesktop", "swaybg"));
+        assert_eq!(detection.reason, "xfdesktop is running");
+
+        assert_eq!(detect_in(&[], &["bash", "plasmashell"]).backend, Backend::Kde);
+        // The desktop still wins.
+        assert_eq!(detect_in(&[("XDG_CURRENT_DESKTOP", "GNOME")], &["plasmashell"]).backend, Backend::Gnome);
+    }
+
+    #[test]
+    fn a_forced_backend_wins() {
+        let detection = detect_from(&Some("feh".to_string()), &|_| Some("GNOME".to_string()), &["gnome-shell".to_string()]);
+
+        assert_eq!(detection.backend, Backend::Wm);
+        assert_eq!(detection.reason, "forced by --backend");
+    }
+}
@@ -0,0 +1,139 @@
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Everything that can go wrong, each with what it was about.
+#[derive(Debug)]
+pub enum Error {
+    // The config file, an environment variable or an option, the message names which.
+    Config(String),
+    // A program we run is missing or failed.
+    Dependency { program: String, message: String },
+    // A program ran but exited with a failure, status is None when a signal killed it.
+    // The monitor is set when it was run for one.
+    Command { program: String, status: Option<i32>, stderr: String, monitor: Option<String> },
+    // The desktop didn't take the wallpaper or the monitors couldn't be read.
+    Backend { backend: String, message: String, monitor: Option<String> },
+    // A download source had nothing usable.
+    Source { name: String, message: String },
+    Io { path: String, error: io::Error },
+    Network { url: String, error: reqwest::Error },
+    // Another wallpaper is running, or none is when one was expected.
+    Instance(String),
+}
+
+impl Error {
+    pub fn io(path: impl fmt::Display, error: io::Error) -> Error {
+        Error::Io {
+            path: path.to_string(),
+            error,
+        }
+    }
+
+    pub fn backend(backend: &str, message: impl fmt::Display) -> Error {
+        Error::Backend {
+            backend: backend.to_string(),
+            message: message.to_string(),
+            monitor: None,
+        }
+    }
+
+    // Names the monitor a Command or Backend error happened on, other errors stay as they are.
+    pub fn on_monitor(mut self, name: &str) -> Error {
+        if let Error::Command { monitor, .. } | Error::Backend { monitor, .. } = &mut self {
+            *monitor = Some(name.to_string());
+        }
+
+        self
+    }
+
+    pub fn dependency(program: &str, message: impl fmt::Display) -> Error {
+        Error::Dependency {
+            program: program.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn source(name: &str, message: impl fmt::Display) -> Error {
+        Error::Source {
+            name: name.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn network(url: &str, error: reqwest::Error) -> Error {
+        Error::Network {
+            url: url.to_string(),
+            error,
+        }
+    }
+
+    // What the wallpaper command exits with, 1 is left to bad command lines.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Config(_) => 2,
+            Error::Dependency { .. } | Error::Command { .. } => 3,
+            Error::Backend { .. } => 4,
+            Error::Source { .. } => 5,
+            Error::Network { .. } => 6,
+            Error::Io { .. } => 7,
+            Error::Instance(_) => 8,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(message) | Error::Instance(message) => write!(f, "{}", message),
+            Error::Dependency { program, message } => write!(f, "{}: {}", program, message),
+            Error::Command { program, status, stderr, monitor } => {
+                match status {
+                    Some(s) => write!(f, "{} exited with {}", program, s)?,
+                    None => write!(f, "{} was killed", program)?,
+                }
+                if let Some(m) = monitor {
+                    write!(f, " on {}", m)?;
+                }
+                match stderr.is_empty() {
+                    true => Ok(()),
+                    false => write!(f, ": {}", stderr),
+                }
+            }
+            Error::Backend { backend, message, monitor: Some(m) } => write!(f, "{} error on {}:{}", backend, m, message),
+            Error::Backend { backend, message, monitor: None } => write!(f, "{} error:{}", backend, message),
+            Error::Source { name, message } => write!(f, "{}: {}", name, message),
+            Error::Io { path, error } => write!(f, "{}:{}", path, error),
+            Error::Network { url, error } => write!(f, "{}:{}", url, error),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { error, .. } => Some(error),
+            Error::Network { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+// Everything X11 says goes back to the X server.
+macro_rules! from_x11 {
+    ($($error: ty),*) => {
+        $(impl From<$error> for Error {
+            fn from(error: $error) -> Error {
+                Error::backend("X11", error)
+            }
+        })*
+    };
+}
+
+from_x11!(
+    x11rb::errors::ConnectError,
+    x11rb::errors::ConnectionError,
+    x11rb::errors::ReplyError,
+    x11rb::errors::ReplyOrIdError
+);
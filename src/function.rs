@@ -1,128 +1,1287 @@
-use crate::fatal;
-use std::error::Error;
-use std::process::{Command, Stdio};
-use std::fs::{read_dir};
+use crate::compose::{self, span};
+use crate::dependency::{Dependency, DBUS_SEND, GSETTINGS, SWAYBG, XFCONF_QUERY};
+use crate::detect::{detect, Backend};
+use crate::error::{Error, Result};
+use crate::monitor::{get_monitors_with, get_wlr_monitors};
+use crate::runner::{CommandRunner, Process, SystemRunner};
+use crate::x11::RootWindow;
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use image::codecs::jpeg::JpegEncoder;
+use std::fs::{canonicalize, create_dir_all, read_to_string, remove_file, File};
 use rand::Rng;
+use regex::Regex;
 use std::env;
 
-pub fn get_resolution() -> Result<Vec<String>, Box<dyn Error>> {
-    check_application("xrandr");
-    // Feh says 'Cm xrandr --listmonitor to determine how Xinerama monitor IDs map to screens / monitors in your setup'.But that is not working in my device.
-    let ret = Command::new("sh")
-        .arg("-c")
-        .arg("xrandr --listactivemonitors|sed 1d|awk '{print $3}'|sed -E 's/^(.+)\\/.+x(.+?)\\/.+$/\\1x\\2/'")
-        .output()?
-        .stdout;
-    let output = String::from_utf8(ret)?;
-    
-    let ret = output.split("\n").filter(|x| x.trim() != "").map(|x| x.to_string()).collect::<Vec<String>>();
+pub fn is_wayland() -> bool {
+    env::var("WAYLAND_DISPLAY").map(|v| !v.is_empty()).unwrap_or(false)
+}
 
-    if ret.len() == 0 {
-        Err("Get resolutions error")?
+pub fn get_cache_dir() -> PathBuf {
+    match env::var("XDG_CACHE_HOME") {
+        Ok(d) if !d.is_empty() => PathBuf::from(d).join("wallpaper"),
+        _ => PathBuf::from(env::var("HOME").unwrap_or_default()).join(".cache/wallpaper"),
     }
+}
+
+pub fn get_state_dir() -> PathBuf {
+    match env::var("XDG_STATE_HOME") {
+        Ok(d) if !d.is_empty() => PathBuf::from(d).join("wallpaper"),
+        _ => PathBuf::from(env::var("HOME").unwrap_or_default()).join(".local/state/wallpaper"),
+    }
+}
 
-    Ok(ret)
+// Socket and pidfile of the running instance, per user even without $XDG_RUNTIME_DIR.
+pub fn get_runtime_file(extension: &str) -> Result<PathBuf> {
+    match env::var("XDG_RUNTIME_DIR") {
+        Ok(d) if !d.is_empty() => Ok(PathBuf::from(d).join(format!("wallpaper.{}", extension))),
+        _ => {
+            let dir = private_dir(&env::temp_dir().join(format!("wallpaper-{}", nix::unistd::getuid())))?;
+            Ok(dir.join(format!("wallpaper.{}", extension)))
+        }
+    }
 }
 
-pub fn check_application(app: &str) {
-    let slice = app.split(" ").collect::<Vec<&str>>();
-    let mut iter = slice.iter();
-    let app = iter.next().unwrap();
+// Anyone can create names in /tmp, so the directory is only used when it is a real directory
+// of ours that nobody else can write to.
+pub fn private_dir(dir: &Path) -> Result<PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
 
-    let mut command = Command::new(app);
-    for p in iter {
-        command.arg(p);   
+    if let Err(e) = std::fs::DirBuilder::new().mode(0o700).create(dir) {
+        if e.kind() != std::io::ErrorKind::AlreadyExists {
+            Err(Error::io(dir.display(), e))?
+        }
     }
-    match command
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()
+
+    let metadata = std::fs::symlink_metadata(dir).map_err(|e| Error::io(dir.display(), e))?;
+    if !metadata.file_type().is_dir()
+        || metadata.uid() != nix::unistd::getuid().as_raw()
+        || metadata.mode() & 0o077 != 0
     {
-        Ok(_out) => {}
-        Err(e) => {
-            if let std::io::ErrorKind::NotFound = e.kind() {
-                fatal!("Program {} does not exist, please install first", app);
-            } else {
-                fatal!("{} takes error:(", app);
-            }
+        Err(Error::io(
+            dir.display(),
+            std::io::Error::new(std::io::ErrorKind::PermissionDenied, "not a directory only we can use"),
+        ))?
+    }
+
+    Ok(dir.to_path_buf())
+}
+
+// Percent-encodes everything but unreserved characters, so spaces, '#' or '%' in names survive.
+pub fn file_uri(path: &str) -> String {
+    let path = canonicalize(path)
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_else(|_e| path.to_string());
+    let mut uri = "file://".to_string();
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => uri.push(b as char),
+            _ => uri.push_str(&format!("%{:02X}", b)),
         }
     }
+
+    uri
 }
 
-pub fn get_random_file(dir: &str) -> String {
-    let mut pictures:Vec<String> = vec!();
-    let mut rand = dir.to_string();
-    match read_dir(dir) {
-        Ok(r) => {
-            for i in r {
-                if let Ok(file) = i {
-                    pictures.push(file.file_name().into_string().unwrap());
-                }
+// Reverse of file_uri, other schemes are left alone.
+pub fn uri_path(uri: &str) -> String {
+    let path = match uri.strip_prefix("file://") {
+        Some(p) => p,
+        None => return uri.to_string(),
+    };
+    let bytes = path.as_bytes();
+    let mut decoded = vec!();
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
             }
-        },
-        Err(_e) => {
-            return rand;
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+pub fn gen_rand_string() -> String {
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                            abcdefghijklmnopqrstuvwxyz\
+                            0123456789";
+    const PASSWORD_LEN: usize = 20;
+    let mut rng = rand::thread_rng();
+
+    (0..PASSWORD_LEN)
+        .map(|_| {
+            let idx = rng.gen_range(0, CHARSET.len());
+            CHARSET[idx] as char
+        })
+        .collect()
+}
+
+pub const SCALE_MODES: [&str; 6] = ["fill", "fit", "center", "tile", "max", "stretch"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScaleMode {
+    // Cover the monitor, cropping what overflows.
+    Fill,
+    // Show the whole image, with borders on the short side.
+    Fit,
+    // Original size in the middle of the monitor.
+    Center,
+    // Repeat the image from the top left corner.
+    Tile,
+    // Like fit, but never enlarge images smaller than the monitor.
+    Max,
+    // Scale to the monitor size without keeping the aspect ratio.
+    Stretch,
+}
+
+impl ScaleMode {
+    pub fn from_name(name: &str) -> Option<ScaleMode> {
+        match name {
+            "fill" => Some(ScaleMode::Fill),
+            "fit" => Some(ScaleMode::Fit),
+            "center" => Some(ScaleMode::Center),
+            "tile" => Some(ScaleMode::Tile),
+            "max" => Some(ScaleMode::Max),
+            "stretch" => Some(ScaleMode::Stretch),
+            _ => None,
         }
     }
-    
-    if pictures.len() > 0 {
-        let mut rng = rand::thread_rng();
-        let rand_index = rng.gen_range(0, pictures.len());
-        rand = format!("{}{}{}", rand, "/", pictures.get(rand_index).unwrap().to_owned());
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScaleMode::Fill => "fill",
+            ScaleMode::Fit => "fit",
+            ScaleMode::Center => "center",
+            ScaleMode::Tile => "tile",
+            ScaleMode::Max => "max",
+            ScaleMode::Stretch => "stretch",
+        }
     }
-    
-    rand
 }
 
-pub trait De {
-    fn wallpaper_dependencies(&self) -> Vec<String>;
+// The wallpaper shown before we started, see --restore-on-exit.
+#[derive(Debug, Clone)]
+pub struct Previous {
+    pub paths: Vec<String>,
+    pub mode: ScaleMode,
+}
+
+// Sent to the thread that rotates.
+pub trait De: Send {
+    fn wallpaper_dependencies(&self) -> Vec<Dependency>;
+
+    fn set_wallpaper(&self, wallpaper_paths: Vec<String>, mode: ScaleMode) -> Result<()>;
+
+    // None when the backend can't tell what is shown.
+    fn previous(&self) -> Option<Previous> {
+        None
+    }
 
-    fn set_wallpaper(&self, wallpaper_paths: Vec<String>);
+    fn restore(&self, previous: Previous) -> Result<()> {
+        self.set_wallpaper(previous.paths, previous.mode)
+    }
 }
 
-pub struct Wm();
+#[derive(Default)]
+pub struct Wm {
+    root: RefCell<Option<RootWindow>>,
+}
 
-pub struct Gnome();
+// The backends below run their programs through `runner`, with_runner hands them a FakeRunner.
+pub struct Gnome {
+    spanned: RefCell<Option<PathBuf>>,
+    // Where spanned canvases are written.
+    canvas_dir: PathBuf,
+    runner: Arc<dyn CommandRunner>,
+}
+
+pub struct Kde {
+    runner: Arc<dyn CommandRunner>,
+    // dbus-send, qdbus6 or qdbus.
+    dbus: &'static str,
+}
+
+pub struct Xfce {
+    runner: Arc<dyn CommandRunner>,
+}
+
+pub struct Wlroots {
+    swaybg: RefCell<Vec<Box<dyn Process>>>,
+    // Holds the pids of our swaybg instances, so the next run stops them.
+    pidfile: Option<PathBuf>,
+    runner: Arc<dyn CommandRunner>,
+}
+
+// The real programs, like new.
+macro_rules! default_new {
+    ($($de: ident),*) => {
+        $(impl Default for $de {
+            fn default() -> $de {
+                $de::new()
+            }
+        })*
+    };
+}
+
+default_new!(Gnome, Kde, Xfce, Wlroots);
 
 impl De for Wm {
-    fn wallpaper_dependencies(&self) -> Vec<String> {
-        return vec!("feh -h".to_string());
+    fn wallpaper_dependencies(&self) -> Vec<Dependency> {
+        // The root window is drawn in process.
+        vec!()
+    }
+
+    fn set_wallpaper(&self, wallpaper_paths: Vec<String>, mode: ScaleMode) -> Result<()> {
+        self.draw(|window| window.set_wallpaper(&wallpaper_paths, mode))
     }
 
-    fn set_wallpaper(&self, wallpaper_paths: Vec<String>) {
-        let mut command = "feh --bg-scale ".to_string();
-        for d in wallpaper_paths.iter() {
-            command = format!("{} {} ", command, d);
+    // What the root window shows when we start, saved before our first wallpaper kills the
+    // setter holding it. The script feh leaves behind may be older than what is shown.
+    fn previous(&self) -> Option<Previous> {
+        let path = get_cache_dir().join(format!("previous-root-{}.png", std::process::id()));
+        create_dir_all(get_cache_dir()).ok()?;
+        match RootWindow::connect().and_then(|r| r.capture(&path)) {
+            Ok(true) => Some(Previous {
+                paths: vec!(path.display().to_string()),
+                mode: ScaleMode::Center,
+            }),
+            _ => None,
         }
-        Command::new("sh")
-            .arg("-c")
-            .arg(command)
-            .output()
-            .expect("faild");
+    }
+
+    // The saved picture covers the whole screen as it was, it is shown as it is.
+    fn restore(&self, previous: Previous) -> Result<()> {
+        let path = previous.paths.first().cloned().unwrap_or_default();
+        let picture = compose::open(&path);
+        let _ = remove_file(&path);
+        let picture = picture?;
+
+        self.draw(|window| window.set_picture(&picture))
+    }
+}
+
+impl Wm {
+    pub fn new() -> Wm {
+        Wm {
+            root: RefCell::new(None),
+        }
+    }
+
+    fn draw<F>(&self, draw: F) -> Result<()>
+    where
+        F: FnOnce(&mut RootWindow) -> Result<()>,
+    {
+        let mut root = self.root.borrow_mut();
+        let window = match root.as_mut() {
+            Some(r) => r,
+            None => root.insert(RootWindow::connect()?),
+        };
+
+        let result = draw(window);
+        if result.is_err() {
+            // Reconnect on the next wallpaper in case the display went away.
+            *root = None;
+        }
+
+        result
     }
 }
 
 impl De for Gnome {
-    fn wallpaper_dependencies(&self) -> Vec<String> {
-        return vec!("gsettings".to_string());
+    fn wallpaper_dependencies(&self) -> Vec<Dependency> {
+        vec!(GSETTINGS)
+    }
+
+    fn set_wallpaper(&self, wallpaper_paths: Vec<String>, mode: ScaleMode) -> Result<()> {
+        if wallpaper_paths.is_empty() {
+            return Ok(());
+        }
+
+        // A canvas that can't be made is reported rather than showing the first picture everywhere.
+        let (path, options) = match self.span(&wallpaper_paths, mode)? {
+            Some(p) => (p, "spanned"),
+            None => (wallpaper_paths[0].to_string(), Gnome::picture_options(mode)),
+        };
+
+        let uri = file_uri(&path);
+        self.set("picture-uri", &uri)?;
+        // Read instead of picture-uri in dark mode since GNOME 42, older versions just reject the key.
+        let _ = self.set("picture-uri-dark", &uri);
+        self.set("picture-options", options)
+    }
+
+    fn previous(&self) -> Option<Previous> {
+        let uri = self.get("picture-uri")?;
+        let mode = match &self.get("picture-options")?[..] {
+            "scaled" => ScaleMode::Fit,
+            "centered" => ScaleMode::Center,
+            "wallpaper" => ScaleMode::Tile,
+            "stretched" => ScaleMode::Stretch,
+            _ => ScaleMode::Fill,
+        };
+
+        Some(Previous {
+            paths: vec!(uri_path(&uri)),
+            mode,
+        })
+    }
+
+    fn restore(&self, previous: Previous) -> Result<()> {
+        self.set_wallpaper(previous.paths, previous.mode)?;
+        // Nothing shows our last canvas any more.
+        if let Some(old) = self.spanned.replace(None) {
+            let _ = remove_file(old);
+        }
+
+        Ok(())
+    }
+}
+
+impl Gnome {
+    pub fn new() -> Gnome {
+        Gnome::with_runner(Arc::new(SystemRunner))
+    }
+
+    pub fn with_runner(runner: Arc<dyn CommandRunner>) -> Gnome {
+        Gnome {
+            spanned: RefCell::new(None),
+            canvas_dir: get_cache_dir(),
+            runner,
+        }
+    }
+
+    // Spanned canvases go to `dir` instead of the cache directory.
+    pub fn with_canvas_dir(mut self, dir: PathBuf) -> Gnome {
+        self.canvas_dir = dir;
+        self
+    }
+
+    fn set(&self, key: &str, value: &str) -> Result<()> {
+        self.runner.run("gsettings", &["set", "org.gnome.desktop.background", key, value])?;
+
+        Ok(())
+    }
+
+    // gsettings prints strings quoted: 'file:///usr/share/backgrounds/a.jpg'
+    fn get(&self, key: &str) -> Option<String> {
+        let output = self.runner.run("gsettings", &["get", "org.gnome.desktop.background", key]).ok()?;
+
+        let value = output.trim().to_string();
+        Some(value.trim_matches('\'').to_string()).filter(|v| !v.is_empty())
+    }
+
+    // GNOME draws a single image, so with several monitors each picture is laid out on one
+    // canvas the size of the whole screen and shown with the spanned option.
+    // The monitors are asked through the runner, a connection to the X server for every
+    // frame of a video would be one too many.
+    fn span(&self, wallpaper_paths: &[String], mode: ScaleMode) -> Result<Option<String>> {
+        if wallpaper_paths.len() < 2 {
+            return Ok(None);
+        }
+        let mut monitors = get_monitors_with(&*self.runner)?;
+        if monitors.len() < 2 {
+            return Ok(None);
+        }
+
+        let left = monitors.iter().map(|m| m.x).min().unwrap_or(0);
+        let top = monitors.iter().map(|m| m.y).min().unwrap_or(0);
+        for m in monitors.iter_mut() {
+            m.x -= left;
+            m.y -= top;
+        }
+        let width = monitors.iter().map(|m| m.x as u32 + m.width).max().unwrap_or(0);
+        let height = monitors.iter().map(|m| m.y as u32 + m.height).max().unwrap_or(0);
+        let canvas = span(wallpaper_paths, &monitors, width, height, mode)?;
+
+        // GNOME caches images by URI, so every canvas gets a new name.
+        let dir = &self.canvas_dir;
+        create_dir_all(dir).map_err(|e| Error::io(dir.display(), e))?;
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let path = dir.join(format!("gnome-span-{}-{}.jpg", millis, gen_rand_string()));
+        let file = File::create(&path).map_err(|e| Error::io(path.display(), e))?;
+        JpegEncoder::new_with_quality(file, 92)
+            .encode_image(&canvas)
+            .map_err(|e| Error::backend("Gnome", format!("{}: {}", path.display(), e)))?;
+
+        if let Some(old) = self.spanned.replace(Some(path.clone())) {
+            let _ = remove_file(old);
+        }
+
+        Ok(Some(path.to_string_lossy().to_string()))
+    }
+
+    fn picture_options(mode: ScaleMode) -> &'static str {
+        match mode {
+            ScaleMode::Fill => "zoom",
+            ScaleMode::Fit | ScaleMode::Max => "scaled",
+            ScaleMode::Center => "centered",
+            ScaleMode::Tile => "wallpaper",
+            ScaleMode::Stretch => "stretched",
+        }
+    }
+}
+
+impl De for Kde {
+    fn wallpaper_dependencies(&self) -> Vec<Dependency> {
+        vec!(DBUS_SEND)
+    }
+
+    fn set_wallpaper(&self, wallpaper_paths: Vec<String>, mode: ScaleMode) -> Result<()> {
+        if wallpaper_paths.is_empty() {
+            return Ok(());
+        }
+
+        let script = Kde::script(&wallpaper_paths, mode);
+        match self.dbus {
+            "dbus-send" => {
+                let script = format!("string:{}", script);
+                self.runner.run(
+                    "dbus-send",
+                    &[
+                        "--session",
+                        "--dest=org.kde.plasmashell",
+                        "--type=method_call",
+                        "/PlasmaShell",
+                        "org.kde.PlasmaShell.evaluateScript",
+                        &script,
+                    ],
+                )?;
+            }
+            qdbus => {
+                self.runner.run(
+                    qdbus,
+                    &["org.kde.plasmashell", "/PlasmaShell", "org.kde.PlasmaShell.evaluateScript", &script],
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // evaluateScript doesn't answer, so the images are read from the plasma config instead.
+    fn previous(&self) -> Option<Previous> {
+        let config = match env::var("XDG_CONFIG_HOME") {
+            Ok(d) if !d.is_empty() => PathBuf::from(d),
+            _ => PathBuf::from(env::var("HOME").ok()?).join(".config"),
+        };
+        let config = read_to_string(config.join("plasma-org.kde.plasma.desktop-appletsrc")).ok()?;
+
+        Kde::parse_appletsrc(&config)
+    }
+}
+
+impl Kde {
+    // Talks to plasmashell with the first of dbus-send, qdbus6 and qdbus found on PATH.
+    pub fn new() -> Kde {
+        Kde::with_runner(Arc::new(SystemRunner), DBUS_SEND.program().unwrap_or("dbus-send"))
+    }
+
+    // `dbus` is dbus-send or a qdbus, they take their arguments differently.
+    pub fn with_runner(runner: Arc<dyn CommandRunner>, dbus: &'static str) -> Kde {
+        Kde { runner, dbus }
+    }
+
+    // Every screen owns one desktop containment per activity, so the image is picked by the
+    // containment's screen to keep the same picture on a monitor whatever activity is shown.
+    fn script(wallpaper_paths: &[String], mode: ScaleMode) -> String {
+        let paths = wallpaper_paths
+            .iter()
+            .map(|p| Kde::quote(&file_uri(p)))
+            .collect::<Vec<String>>()
+            .join(",");
+
+        format!(
+            "var paths = [{}];\
+            var all = desktops();\
+            for (var i = 0; i < all.length; i++) {{\
+                var d = all[i];\
+                var screen = d.screen < 0 ? i : d.screen;\
+                d.wallpaperPlugin = \"org.kde.image\";\
+                d.currentConfigGroup = Array(\"Wallpaper\", \"org.kde.image\", \"General\");\
+                d.writeConfig(\"Image\", paths[screen % paths.length]);\
+                d.writeConfig(\"FillMode\", {});\
+            }}",
+            paths,
+            Kde::fill_mode(mode)
+        )
+    }
+
+    // [Containments][1][Wallpaper][org.kde.image][General]
+    // FillMode=2
+    // Image=file:///home/me/a.jpg
+    fn parse_appletsrc(config: &str) -> Option<Previous> {
+        let mut paths = vec!();
+        let mut fill_mode = None;
+        let mut in_image = false;
+        for line in config.lines().map(|l| l.trim()) {
+            if line.starts_with('[') {
+                in_image = line.ends_with("[Wallpaper][org.kde.image][General]");
+            } else if let (true, Some(image)) = (in_image, line.strip_prefix("Image=")) {
+                paths.push(uri_path(image));
+            } else if let (true, Some(mode)) = (in_image, line.strip_prefix("FillMode=")) {
+                fill_mode = fill_mode.or_else(|| mode.parse::<u8>().ok());
+            }
+        }
+        if paths.is_empty() {
+            return None;
+        }
+
+        let mode = [ScaleMode::Stretch, ScaleMode::Fit, ScaleMode::Fill, ScaleMode::Tile, ScaleMode::Center]
+            .iter()
+            .find(|m| Some(Kde::fill_mode(**m)) == fill_mode)
+            .copied()
+            .unwrap_or(ScaleMode::Fill);
+
+        Some(Previous { paths, mode })
+    }
+
+    // Values of the Qt Image.fillMode enum used by the org.kde.image plugin.
+    fn fill_mode(mode: ScaleMode) -> u8 {
+        match mode {
+            ScaleMode::Stretch => 0,
+            ScaleMode::Fit | ScaleMode::Max => 1,
+            ScaleMode::Fill => 2,
+            ScaleMode::Tile => 3,
+            ScaleMode::Center => 6,
+        }
+    }
+
+    fn quote(s: &str) -> String {
+        let mut quoted = "\"".to_string();
+        for c in s.chars() {
+            match c {
+                '"' | '\\' => {
+                    quoted.push('\\');
+                    quoted.push(c);
+                }
+                '\n' => quoted.push_str("\\n"),
+                _ => quoted.push(c),
+            }
+        }
+        quoted.push('"');
+
+        quoted
+    }
+}
+
+impl De for Xfce {
+    fn wallpaper_dependencies(&self) -> Vec<Dependency> {
+        vec!(XFCONF_QUERY)
+    }
+
+    fn set_wallpaper(&self, wallpaper_paths: Vec<String>, mode: ScaleMode) -> Result<()> {
+        if wallpaper_paths.is_empty() {
+            return Ok(());
+        }
+
+        let names = get_monitors_with(&*self.runner)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|m| m.name)
+            .collect::<Vec<String>>();
+        let mut properties = self.properties();
+        if properties.is_empty() {
+            properties = names
+                .iter()
+                .map(|n| (n.to_string(), format!("/backdrop/screen0/monitor{}/workspace0/last-image", n)))
+                .collect();
+        }
+
+        let mut monitors: Vec<String> = vec!();
+        for (monitor, _) in properties.iter() {
+            if !monitors.contains(monitor) {
+                monitors.push(monitor.to_string());
+            }
+        }
+
+        for (monitor, property) in properties.iter() {
+            let index = Xfce::monitor_index(monitor, &names)
+                .or_else(|| monitors.iter().position(|m| m == monitor))
+                .unwrap_or(0);
+            let path = &wallpaper_paths[index % wallpaper_paths.len()];
+
+            let style = property.replace("/last-image", "/image-style");
+            self.set_property(property, "string", path)
+                .and_then(|_| self.set_property(&style, "int", &Xfce::image_style(mode).to_string()))
+                .map_err(|e| e.on_monitor(monitor))?;
+        }
+
+        Ok(())
+    }
+
+    // The first workspace of every monitor, in the order set_wallpaper hands out the paths.
+    fn previous(&self) -> Option<Previous> {
+        let names = get_monitors_with(&*self.runner)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|m| m.name)
+            .collect::<Vec<String>>();
+        let mut images: Vec<(usize, String, String)> = vec!();
+        let mut monitors: Vec<String> = vec!();
+        for (monitor, property) in self.properties().iter() {
+            if monitors.contains(monitor) {
+                continue;
+            }
+            monitors.push(monitor.to_string());
+            if let Some(image) = self.get_property(property) {
+                let index = Xfce::monitor_index(monitor, &names).unwrap_or(monitors.len() - 1);
+                images.push((index, image, property.replace("/last-image", "/image-style")));
+            }
+        }
+        images.sort_by_key(|i| i.0);
+
+        let style = images.first().and_then(|i| self.get_property(&i.2));
+        let mode = [ScaleMode::Center, ScaleMode::Tile, ScaleMode::Stretch, ScaleMode::Fit, ScaleMode::Fill]
+            .iter()
+            .find(|m| Some(Xfce::image_style(**m).to_string()) == style)
+            .copied()
+            .unwrap_or(ScaleMode::Fill);
+        let paths = images.into_iter().map(|i| i.1).collect::<Vec<String>>();
+        if paths.is_empty() {
+            return None;
+        }
+
+        Some(Previous { paths, mode })
+    }
+}
+
+impl Xfce {
+    pub fn new() -> Xfce {
+        Xfce::with_runner(Arc::new(SystemRunner))
+    }
+
+    pub fn with_runner(runner: Arc<dyn CommandRunner>) -> Xfce {
+        Xfce { runner }
+    }
+
+    // Returns every (monitor, property) pair holding a workspace image, in the order xfconf lists them.
+    fn properties(&self) -> Vec<(String, String)> {
+        let output = match self.runner.run("xfconf-query", &["-c", "xfce4-desktop", "-l"]) {
+            Ok(o) => o,
+            Err(_e) => return vec!(),
+        };
+        let re = Regex::new(r"^/backdrop/screen\d+/monitor([^/]+)/workspace\d+/last-image$").unwrap();
+
+        output
+            .lines()
+            .filter_map(|l| re.captures(l.trim()).map(|c| (c[1].to_string(), l.trim().to_string())))
+            .collect()
+    }
+
+    fn get_property(&self, property: &str) -> Option<String> {
+        let output = self.runner.run("xfconf-query", &["-c", "xfce4-desktop", "-p", property]).ok()?;
+
+        Some(output.trim().to_string()).filter(|v| !v.is_empty())
+    }
+
+    // -n only creates the property when it is missing, so it is safe to pass every time.
+    fn set_property(&self, property: &str, kind: &str, value: &str) -> Result<()> {
+        self.runner.run(
+            "xfconf-query",
+            &["-c", "xfce4-desktop", "-p", property, "-n", "-t", kind, "-s", value],
+        )?;
+
+        Ok(())
+    }
+
+    fn image_style(mode: ScaleMode) -> u8 {
+        match mode {
+            ScaleMode::Center => 1,
+            ScaleMode::Tile => 2,
+            ScaleMode::Stretch => 3,
+            ScaleMode::Fit | ScaleMode::Max => 4,
+            ScaleMode::Fill => 5,
+        }
+    }
+
+    // Xfce names monitors after their output (monitorDP-1) since 4.14 and by index (monitor0) before.
+    fn monitor_index(monitor: &str, names: &[String]) -> Option<usize> {
+        if let Some(i) = names.iter().position(|n| n == monitor) {
+            return Some(i);
+        }
+
+        monitor.parse::<usize>().ok()
+    }
+}
+
+impl De for Wlroots {
+    fn wallpaper_dependencies(&self) -> Vec<Dependency> {
+        vec!(SWAYBG)
+    }
+
+    fn set_wallpaper(&self, wallpaper_paths: Vec<String>, mode: ScaleMode) -> Result<()> {
+        if wallpaper_paths.is_empty() {
+            return Ok(());
+        }
+
+        let mut outputs = get_wlr_monitors(&*self.runner)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|m| m.name)
+            .collect::<Vec<String>>();
+        if outputs.is_empty() {
+            outputs.push("*".to_string());
+        }
+
+        let mut started = vec!();
+        for (i, output) in outputs.iter().enumerate() {
+            let path = &wallpaper_paths[i % wallpaper_paths.len()];
+            let child = self
+                .runner
+                .spawn("swaybg", &["-o", output, "-i", path, "-m", Wlroots::swaybg_mode(mode)]);
+            match child {
+                Ok(c) => started.push(c),
+                Err(e) => {
+                    // What started already is left to the next wallpaper to replace.
+                    self.swaybg.borrow_mut().append(&mut started);
+                    return Err(e.on_monitor(output));
+                }
+            }
+        }
+
+        // The new swaybg instances are mapped on top of the old ones, so these can go without a blank frame.
+        // The ones of an earlier run (crashed, replaced...) are only known by their pid.
+        let ours = self.swaybg.borrow().iter().map(|p| p.pid()).collect::<Vec<u32>>();
+        for pid in self.saved_pids().into_iter().filter(|p| !ours.contains(p)) {
+            let _ = self.runner.kill("swaybg", pid);
+        }
+        for mut old in self.swaybg.replace(started) {
+            old.stop();
+        }
+        self.save_pids()
+    }
+}
+
+impl Wlroots {
+    // Keeps the swaybg pids in the runtime directory.
+    pub fn new() -> Wlroots {
+        let wlroots = Wlroots::with_runner(Arc::new(SystemRunner));
+        match get_runtime_file("swaybg") {
+            Ok(pidfile) => wlroots.with_pidfile(pidfile),
+            Err(_e) => wlroots,
+        }
+    }
+
+    pub fn with_runner(runner: Arc<dyn CommandRunner>) -> Wlroots {
+        Wlroots {
+            swaybg: RefCell::new(vec!()),
+            pidfile: None,
+            runner,
+        }
+    }
+
+    // Stops the swaybg instances listed in `pidfile` by an earlier run and lists ours there.
+    pub fn with_pidfile(mut self, pidfile: PathBuf) -> Wlroots {
+        self.pidfile = Some(pidfile);
+        self
+    }
+
+    fn saved_pids(&self) -> Vec<u32> {
+        self.pidfile
+            .as_ref()
+            .and_then(|p| read_to_string(p).ok())
+            .map(|s| s.lines().filter_map(|l| l.trim().parse().ok()).collect())
+            .unwrap_or_default()
+    }
+
+    fn save_pids(&self) -> Result<()> {
+        let pidfile = match &self.pidfile {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let pids = self.swaybg.borrow().iter().map(|p| format!("{}\n", p.pid())).collect::<String>();
+
+        std::fs::write(pidfile, pids).map_err(|e| Error::io(pidfile.display(), e))
+    }
+
+    fn swaybg_mode(mode: ScaleMode) -> &'static str {
+        match mode {
+            ScaleMode::Fill => "fill",
+            ScaleMode::Fit | ScaleMode::Max => "fit",
+            ScaleMode::Center => "center",
+            ScaleMode::Tile => "tile",
+            ScaleMode::Stretch => "stretch",
+        }
+    }
+}
+
+pub struct Hyprpaper {
+    dir: PathBuf,
+}
+
+impl De for Hyprpaper {
+    fn wallpaper_dependencies(&self) -> Vec<Dependency> {
+        // Hyprpaper is reached through its socket, so there is no program to run.
+        vec!()
+    }
+
+    fn set_wallpaper(&self, wallpaper_paths: Vec<String>, mode: ScaleMode) -> Result<()> {
+        self.apply(&wallpaper_paths, mode)
+    }
+
+    // listactive answers one "<monitor> = <path>" line per monitor.
+    fn previous(&self) -> Option<Previous> {
+        let reply = Hyprpaper::send(&self.dir.join(".hyprpaper.sock"), "listactive").ok()?;
+        let active = reply
+            .lines()
+            .filter_map(|l| l.split_once(" = "))
+            .map(|(m, p)| (m.trim().to_string(), p.trim().to_string()))
+            .collect::<Vec<(String, String)>>();
+        let mut paths = self
+            .monitors()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|m| active.iter().find(|a| &a.0 == m).map(|a| a.1.clone()))
+            .collect::<Vec<String>>();
+        if paths.is_empty() {
+            paths = active.into_iter().map(|a| a.1).collect();
+        }
+        if paths.is_empty() {
+            return None;
+        }
+
+        Some(Previous {
+            paths,
+            mode: ScaleMode::Fill,
+        })
+    }
+}
+
+impl Hyprpaper {
+    pub fn new(dir: PathBuf) -> Hyprpaper {
+        Hyprpaper { dir }
+    }
+
+    // $XDG_RUNTIME_DIR/hypr/<instance>/ since Hyprland 0.40, /tmp/hypr/<instance>/ before.
+    pub fn detect() -> Option<Hyprpaper> {
+        Hyprpaper::detect_with(&|key| env::var(key).ok())
+    }
+
+    // Like detect, with the environment looked up through `env`.
+    pub fn detect_with(env: &dyn Fn(&str) -> Option<String>) -> Option<Hyprpaper> {
+        let instance = env("HYPRLAND_INSTANCE_SIGNATURE")?;
+        let mut dirs = vec!();
+        if let Some(runtime) = env("XDG_RUNTIME_DIR") {
+            dirs.push(PathBuf::from(runtime).join("hypr").join(&instance));
+        }
+        dirs.push(PathBuf::from("/tmp/hypr").join(&instance));
+
+        dirs.into_iter()
+            .find(|d| d.join(".hyprpaper.sock").exists())
+            .map(Hyprpaper::new)
+    }
+
+    fn apply(&self, wallpaper_paths: &[String], mode: ScaleMode) -> Result<()> {
+        if wallpaper_paths.is_empty() {
+            return Ok(());
+        }
+
+        // Hyprpaper only knows cover (the default), contain and tile.
+        let prefix = match mode {
+            ScaleMode::Fit | ScaleMode::Max => "contain:",
+            ScaleMode::Tile => "tile:",
+            _ => "",
+        };
+        let monitors = self.monitors().unwrap_or_default();
+        for p in wallpaper_paths.iter() {
+            self.request(&format!("preload {}", p))?;
+        }
+        if monitors.is_empty() {
+            self.request(&format!("wallpaper ,{}{}", prefix, wallpaper_paths[0]))?;
+        }
+        for (i, m) in monitors.iter().enumerate() {
+            self.request(&format!("wallpaper {},{}{}", m, prefix, wallpaper_paths[i % wallpaper_paths.len()]))
+                .map_err(|e| e.on_monitor(m))?;
+        }
+        self.request("unload unused")?;
+
+        Ok(())
+    }
+
+    // Hyprland's own socket lists the monitors without spawning hyprctl for every frame.
+    fn monitors(&self) -> Result<Vec<String>> {
+        let reply = Hyprpaper::send(&self.dir.join(".socket.sock"), "j/monitors")?;
+        let monitors: serde_json::Value =
+            serde_json::from_str(&reply).map_err(|e| Error::backend("Hyprland", format!("j/monitors: {}", e)))?;
+        let monitors = monitors.as_array().cloned().unwrap_or_default();
+
+        Ok(monitors
+            .iter()
+            .filter_map(|m| m["name"].as_str().map(|n| n.to_string()))
+            .collect())
+    }
+
+    fn request(&self, command: &str) -> Result<()> {
+        let reply = Hyprpaper::send(&self.dir.join(".hyprpaper.sock"), command)?;
+        if reply.trim() != "ok" {
+            Err(Error::backend("Hyprpaper", format!("{}:{}", command, reply.trim())))?
+        }
+
+        Ok(())
     }
 
-    fn set_wallpaper(&self, wallpaper_paths: Vec<String>) {
-        let command = format!("gsettings set org.gnome.desktop.background picture-uri file:///{}", wallpaper_paths.get(0).unwrap_or(&"".to_string()));
-        Command::new("sh")
-            .arg("-c")
-            .arg(command)
-            .output()
-            .expect("faild");
+    // Both daemons take one command per connection and close it after replying.
+    fn send(socket: &Path, command: &str) -> Result<String> {
+        let exchange = || -> std::io::Result<String> {
+            let mut stream = UnixStream::connect(socket)?;
+            stream.write_all(command.as_bytes())?;
+            stream.shutdown(std::net::Shutdown::Write)?;
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply)?;
+
+            Ok(reply)
+        };
+
+        exchange().map_err(|e| Error::io(socket.display(), e))
     }
 }
 
-pub fn get_de() -> Box<dyn De> {
-    let de = &env::var("XDG_CURRENT_DESKTOP").unwrap_or("wm".to_string())[..];
+pub fn get_de(backend: &Option<String>) -> Result<Box<dyn De>> {
+    Ok(match detect(backend).backend {
+        Backend::Gnome => Box::new(Gnome::new()),
+        Backend::Kde => Box::new(Kde::new()),
+        Backend::Xfce => Box::new(Xfce::new()),
+        Backend::Wlroots => Box::new(Wlroots::new()),
+        Backend::Hyprpaper => match Hyprpaper::detect() {
+            Some(h) => Box::new(h),
+            None => Err(Error::backend("Hyprpaper", "socket not found, please start hyprpaper first"))?,
+        },
+        Backend::Wm => Box::new(Wm::new()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::FakeRunner;
+    use std::fs::{read_dir, remove_dir_all};
+    use std::os::unix::net::UnixListener;
+    use std::sync::Mutex;
+    use std::thread;
+
+    // Reaches the programs as it is, no shell would leave it alone.
+    const PATH: &str = "/w/it's a $HOME.jpg";
+    const OTHER: &str = "/w/b.png";
+
+    // Two monitors whether they are asked from xrandr or from sway.
+    fn runner() -> FakeRunner {
+        let runner = FakeRunner::new();
+        runner.reply(
+            "xrandr",
+            "Monitors: 2\n 0: +*DP-1 2560/597x1440/336+0+0  DP-1\n 1: +HDMI-1 1920/527x1080/296+2560+0  HDMI-1\n",
+        );
+        runner.reply(
+            "swaymsg",
+            r#"[{"name": "DP-1", "active": true, "rect": {"x": 0, "y": 0}, "current_mode": {"width": 2560, "height": 1440}},
+                {"name": "HDMI-1", "active": true, "rect": {"x": 2560, "y": 0}, "current_mode": {"width": 1920, "height": 1080}}]"#,
+        );
+        runner
+    }
+
+    fn calls_of(runner: &FakeRunner, program: &str) -> Vec<Vec<String>> {
+        runner.calls().into_iter().filter(|c| c[0] == program).collect()
+    }
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn gnome_sets_the_uri_and_options() {
+        let runner = FakeRunner::new();
+        Gnome::with_runner(Arc::new(runner.clone()))
+            .set_wallpaper(vec!(PATH.to_string()), ScaleMode::Fill)
+            .unwrap();
+
+        let uri = "file:///w/it%27s%20a%20%24HOME.jpg";
+        assert_eq!(
+            runner.calls(),
+            vec!(
+                argv(&["gsettings", "set", "org.gnome.desktop.background", "picture-uri", uri]),
+                argv(&["gsettings", "set", "org.gnome.desktop.background", "picture-uri-dark", uri]),
+                argv(&["gsettings", "set", "org.gnome.desktop.background", "picture-options", "zoom"]),
+            )
+        );
+    }
+
+    #[test]
+    fn gnome_spans_through_the_runner_and_keeps_one_canvas() {
+        let dir = temp_dir();
+        let paths = ["a.png", "b.png"]
+            .iter()
+            .map(|f| {
+                let path = dir.join(f).display().to_string();
+                image::RgbImage::new(4, 3).save(&path).unwrap();
+                path
+            })
+            .collect::<Vec<String>>();
+        let runner = FakeRunner::new();
+        runner.reply("xrandr", "Monitors: 2\n 0: +*DP-1 16/1x9/1+0+0  DP-1\n 1: +HDMI-1 16/1x9/1+16+0  HDMI-1\n");
+        let canvases = dir.join("canvases");
+        let gnome = Gnome::with_runner(Arc::new(runner.clone())).with_canvas_dir(canvases.clone());
+
+        // As for the frames of a video, no X connection is left behind for any of them.
+        for _ in 0..5 {
+            gnome.set_wallpaper(paths.clone(), ScaleMode::Fill).unwrap();
+        }
+
+        assert_eq!(read_dir(&canvases).unwrap().count(), 1);
+        assert!(runner.calls().iter().all(|c| c[0] == "xrandr" || c[0] == "gsettings"));
+        assert_eq!(
+            calls_of(&runner, "gsettings").last().unwrap(),
+            &argv(&["gsettings", "set", "org.gnome.desktop.background", "picture-options", "spanned"])
+        );
+        let _ = remove_dir_all(dir);
+    }
+
+    #[test]
+    fn kde_evaluates_one_script() {
+        let paths = vec!(PATH.to_string(), OTHER.to_string());
+        let script = Kde::script(&paths, ScaleMode::Fit);
+        assert!(script.starts_with(r#"var paths = ["file:///w/it%27s%20a%20%24HOME.jpg","file:///w/b.png"];"#), "{}", script);
+
+        let calls = |dbus: &'static str| {
+            let runner = FakeRunner::new();
+            Kde::with_runner(Arc::new(runner.clone()), dbus).set_wallpaper(paths.clone(), ScaleMode::Fit).unwrap();
+            runner.calls()
+        };
+        assert_eq!(
+            calls("dbus-send"),
+            vec!(argv(&[
+                "dbus-send",
+                "--session",
+                "--dest=org.kde.plasmashell",
+                "--type=method_call",
+                "/PlasmaShell",
+                "org.kde.PlasmaShell.evaluateScript",
+                &format!("string:{}", script),
+            ]))
+        );
+        assert_eq!(
+            calls("qdbus6"),
+            vec!(argv(&["qdbus6", "org.kde.plasmashell", "/PlasmaShell", "org.kde.PlasmaShell.evaluateScript", &script]))
+        );
+        assert_eq!(
+            calls("qdbus"),
+            vec!(argv(&["qdbus", "org.kde.plasmashell", "/PlasmaShell", "org.kde.PlasmaShell.evaluateScript", &script]))
+        );
+    }
+
+    #[test]
+    fn xfce_sets_every_workspace_of_every_monitor() {
+        let runner = runner();
+        runner.reply(
+            "xfconf-query",
+            "/backdrop/screen0/monitorDP-1/workspace0/last-image\n\
+             /backdrop/screen0/monitorDP-1/workspace1/last-image\n\
+             /backdrop/screen0/monitorHDMI-1/workspace0/last-image\n\
+             /backdrop/screen0/monitorHDMI-1/workspace0/image-style\n",
+        );
+        Xfce::with_runner(Arc::new(runner.clone()))
+            .set_wallpaper(vec!(PATH.to_string(), OTHER.to_string()), ScaleMode::Center)
+            .unwrap();
 
-    if de == "ubuntu:GNOME" {
-        return Box::new(Gnome());
-    } else {
-        return Box::new(Wm());
+        let set = |property: &str, kind: &str, value: &str| {
+            argv(&["xfconf-query", "-c", "xfce4-desktop", "-p", property, "-n", "-t", kind, "-s", value])
+        };
+        assert_eq!(
+            calls_of(&runner, "xfconf-query"),
+            vec!(
+                argv(&["xfconf-query", "-c", "xfce4-desktop", "-l"]),
+                set("/backdrop/screen0/monitorDP-1/workspace0/last-image", "string", PATH),
+                set("/backdrop/screen0/monitorDP-1/workspace0/image-style", "int", "1"),
+                set("/backdrop/screen0/monitorDP-1/workspace1/last-image", "string", PATH),
+                set("/backdrop/screen0/monitorDP-1/workspace1/image-style", "int", "1"),
+                set("/backdrop/screen0/monitorHDMI-1/workspace0/last-image", "string", OTHER),
+                set("/backdrop/screen0/monitorHDMI-1/workspace0/image-style", "int", "1"),
+            )
+        );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn wlroots_starts_swaybg_for_every_output() {
+        let runner = runner();
+        Wlroots::with_runner(Arc::new(runner.clone()))
+            .set_wallpaper(vec!(PATH.to_string(), OTHER.to_string()), ScaleMode::Tile)
+            .unwrap();
+
+        assert_eq!(
+            calls_of(&runner, "swaybg"),
+            vec!(
+                argv(&["swaybg", "-o", "DP-1", "-i", PATH, "-m", "tile"]),
+                argv(&["swaybg", "-o", "HDMI-1", "-i", OTHER, "-m", "tile"]),
+            )
+        );
+    }
+
+    #[test]
+    fn errors_name_the_monitor() {
+        let runner = runner();
+        runner.fail("swaybg", Some(1), "Unable to load image");
+        runner.fail("xfconf-query", Some(1), "Property is locked");
+        let paths = vec!(PATH.to_string(), OTHER.to_string());
+
+        let e = Wlroots::with_runner(Arc::new(runner.clone()))
+            .set_wallpaper(paths.clone(), ScaleMode::Fill)
+            .unwrap_err();
+        assert_eq!(e.to_string(), "swaybg exited with 1 on DP-1: Unable to load image");
+        let e = Xfce::with_runner(Arc::new(runner)).set_wallpaper(paths, ScaleMode::Fill).unwrap_err();
+        assert_eq!(e.to_string(), "xfconf-query exited with 1 on DP-1: Property is locked");
+        assert_eq!(Error::Config("bad".to_string()).on_monitor("DP-1").to_string(), "bad");
+    }
+
+    #[test]
+    fn wlroots_stops_the_swaybg_of_an_earlier_run() {
+        let dir = temp_dir();
+        let pidfile = dir.join("wallpaper.swaybg");
+        let paths = vec!(PATH.to_string(), OTHER.to_string());
+
+        // Crashed with its two swaybg running, they get pids 100 and 101.
+        let first = runner();
+        Wlroots::with_runner(Arc::new(first.clone()))
+            .with_pidfile(pidfile.clone())
+            .set_wallpaper(paths.clone(), ScaleMode::Fill)
+            .unwrap();
+        assert_eq!(read_to_string(&pidfile).unwrap(), "100\n101\n");
+
+        let second = runner();
+        let wlroots = Wlroots::with_runner(Arc::new(second.clone())).with_pidfile(pidfile.clone());
+        wlroots.set_wallpaper(paths.clone(), ScaleMode::Fill).unwrap();
+        assert_eq!(calls_of(&second, "kill"), vec!(argv(&["kill", "100"]), argv(&["kill", "101"])));
+
+        // Ours are stopped as processes, not killed by pid once more.
+        wlroots.set_wallpaper(paths, ScaleMode::Fill).unwrap();
+        assert_eq!(calls_of(&second, "kill").len(), 2);
+        assert_eq!(read_to_string(&pidfile).unwrap(), "102\n103\n");
+        let _ = remove_dir_all(dir);
+    }
+
+    #[test]
+    fn a_failing_program_is_a_command_error() {
+        let runner = FakeRunner::new();
+        runner.fail("gsettings", Some(1), "No such schema “org.gnome.desktop.background”");
+
+        let e = Gnome::with_runner(Arc::new(runner.clone()))
+            .set_wallpaper(vec!(PATH.to_string()), ScaleMode::Fill)
+            .unwrap_err();
+
+        match &e {
+            Error::Command { program, status, stderr, .. } => {
+                assert_eq!((program.as_str(), *status), ("gsettings", Some(1)));
+                assert!(stderr.starts_with("No such schema"));
+            }
+            e => panic!("not a command error: {:?}", e),
+        }
+        assert_eq!(e.exit_code(), 3);
+        // Nothing more is run once the first one fails.
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("wallpaper-test-{}", gen_rand_string()));
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Answers every connection on `socket` like hyprpaper does: one command, one reply.
+    fn serve<F>(socket: PathBuf, answer: F) -> Arc<Mutex<Vec<String>>>
+    where
+        F: Fn(&str) -> String + Send + 'static,
+    {
+        let received = Arc::new(Mutex::new(vec!()));
+        let listener = UnixListener::bind(&socket).unwrap();
+        let log = received.clone();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut command = String::new();
+                stream.read_to_string(&mut command).unwrap();
+                let reply = answer(&command);
+                log.lock().unwrap().push(command);
+                let _ = stream.write_all(reply.as_bytes());
+            }
+        });
+
+        received
+    }
+
+    fn hyprland(dir: &Path) {
+        serve(dir.join(".socket.sock"), |command| match command {
+            "j/monitors" => r#"[{"id": 0, "name": "DP-1"}, {"id": 1, "name": "HDMI-A-1"}]"#.to_string(),
+            _ => "unknown request".to_string(),
+        });
+    }
+
+    #[test]
+    fn hyprpaper_preloads_sets_every_monitor_and_unloads() {
+        let dir = temp_dir();
+        hyprland(&dir);
+        let received = serve(dir.join(".hyprpaper.sock"), |_c| "ok".to_string());
+
+        let paths = vec!("/w/a b.jpg".to_string(), "/w/c.png".to_string());
+        Hyprpaper::new(dir.clone()).set_wallpaper(paths, ScaleMode::Fit).unwrap();
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec!(
+                "preload /w/a b.jpg",
+                "preload /w/c.png",
+                "wallpaper DP-1,contain:/w/a b.jpg",
+                "wallpaper HDMI-A-1,contain:/w/c.png",
+                "unload unused",
+            )
+        );
+        let _ = remove_dir_all(dir);
+    }
+
+    #[test]
+    fn hyprpaper_reports_a_refused_command() {
+        let dir = temp_dir();
+        hyprland(&dir);
+        let received = serve(dir.join(".hyprpaper.sock"), |c| match c.starts_with("preload") {
+            true => "ok".to_string(),
+            false => "wallpaper failed (not preloaded)".to_string(),
+        });
+
+        let e = Hyprpaper::new(dir.clone())
+            .set_wallpaper(vec!("/w/a.jpg".to_string()), ScaleMode::Fill)
+            .unwrap_err();
+
+        assert!(matches!(e, Error::Backend { .. }), "{:?}", e);
+        assert_eq!(e.to_string(), "Hyprpaper error on DP-1:wallpaper DP-1,/w/a.jpg:wallpaper failed (not preloaded)");
+        // Nothing more is sent after the refusal.
+        assert_eq!(received.lock().unwrap().len(), 2);
+        let _ = remove_dir_all(dir);
+    }
+
+    #[test]
+    fn the_runtime_dir_is_ours_alone() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let dir = temp_dir();
+        let runtime = dir.join("runtime");
+        private_dir(&runtime).unwrap();
+        assert_eq!(std::fs::metadata(&runtime).unwrap().permissions().mode() & 0o777, 0o700);
+        // Already there is fine.
+        private_dir(&runtime).unwrap();
+
+        // Someone else's directory planted under the name, or one anybody can write to.
+        let planted = dir.join("planted");
+        symlink(&runtime, &planted).unwrap();
+        assert!(matches!(private_dir(&planted), Err(Error::Io { .. })));
+        std::fs::set_permissions(&runtime, std::fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(matches!(private_dir(&runtime), Err(Error::Io { .. })));
+        let _ = remove_dir_all(dir);
+    }
+}
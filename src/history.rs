@@ -0,0 +1,185 @@
+use crate::error::{Error, Result};
+use crate::function::get_state_dir;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{create_dir_all, read_to_string, write};
+use std::path::PathBuf;
+
+// Wallpapers kept for every monitor.
+const HISTORY_SIZE: usize = 100;
+
+// The wallpapers every monitor showed, oldest first. Moving back does not drop anything,
+// a new wallpaper is added after the newest entry and takes us back there.
+pub struct History {
+    path: PathBuf,
+    monitors: HashMap<String, VecDeque<String>>,
+    back: usize,
+}
+
+impl History {
+    pub fn load() -> History {
+        History::load_from(get_state_dir().join("history.json"))
+    }
+
+    // Kept in `path` instead of the state directory.
+    pub fn load_from(path: PathBuf) -> History {
+        let monitors = read_to_string(&path)
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default();
+
+        History {
+            path,
+            monitors,
+            back: 0,
+        }
+    }
+
+    // The wallpapers are kept even when the history file can't be written.
+    pub fn push(&mut self, monitors: &[String], wallpapers: &[String]) -> Result<()> {
+        self.back = 0;
+        for (m, w) in monitors.iter().zip(wallpapers.iter()) {
+            let entries = self.monitors.entry(m.to_string()).or_default();
+            entries.push_back(w.to_string());
+            while entries.len() > HISTORY_SIZE {
+                entries.pop_front();
+            }
+        }
+
+        self.save().map_err(|e| Error::io(self.path.display(), e))
+    }
+
+    pub fn prev(&mut self, monitors: &[String]) -> Option<Vec<String>> {
+        let wallpapers = self.at(monitors, self.back + 1)?;
+        self.back += 1;
+
+        Some(wallpapers)
+    }
+
+    // None once we are back at the newest wallpapers, a new one has to be picked then.
+    pub fn next(&mut self, monitors: &[String]) -> Option<Vec<String>> {
+        if self.back == 0 {
+            return None;
+        }
+        self.back -= 1;
+
+        self.at(monitors, self.back)
+    }
+
+    pub fn current(&self, monitors: &[String]) -> Option<Vec<String>> {
+        self.at(monitors, self.back)
+    }
+
+    // A monitor with a shorter history (plugged later) keeps its oldest wallpaper, one without
+    // any keeps the current wallpaper of the first monitor that has one.
+    fn at(&self, monitors: &[String], back: usize) -> Option<Vec<String>> {
+        let entry = |m: &String, back: usize| {
+            let entries = self.monitors.get(m).filter(|e| !e.is_empty())?;
+            entries.get(entries.len().saturating_sub(back + 1)).cloned()
+        };
+        let longest = monitors
+            .iter()
+            .filter_map(|m| self.monitors.get(m))
+            .map(|e| e.len())
+            .max()?;
+        if back >= longest {
+            return None;
+        }
+        let current = monitors.iter().find_map(|m| entry(m, self.back))?;
+
+        Some(monitors.iter().map(|m| entry(m, back).unwrap_or_else(|| current.clone())).collect())
+    }
+
+    pub fn list(&self, monitors: &[String]) -> String {
+        let mut ret = String::new();
+        for m in monitors.iter() {
+            ret.push_str(&format!("{}:\n", m));
+            if let Some(entries) = self.monitors.get(m) {
+                for (i, w) in entries.iter().rev().enumerate() {
+                    let marker = if i == self.back { "*" } else { " " };
+                    ret.push_str(&format!("{} {}\n", marker, w));
+                }
+            }
+        }
+
+        ret
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            create_dir_all(dir)?;
+        }
+
+        write(&self.path, serde_json::to_string(&self.monitors)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history() -> History {
+        History {
+            path: PathBuf::new(),
+            monitors: HashMap::new(),
+            back: 0,
+        }
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn history_of(monitors: &[(&str, &[&str])]) -> History {
+        let mut history = history();
+        for (m, wallpapers) in monitors.iter() {
+            history.monitors.insert(m.to_string(), wallpapers.iter().map(|w| w.to_string()).collect());
+        }
+
+        history
+    }
+
+    // Nothing to write to, the entries are still kept.
+    fn push(history: &mut History, monitors: &[&str], wallpapers: &[&str]) {
+        let _ = history.push(&names(monitors), &names(wallpapers));
+    }
+
+    #[test]
+    fn a_push_after_going_back_becomes_the_newest() {
+        let mut history = history();
+        let monitors = names(&["DP-1"]);
+        for w in ["a", "b", "c"].iter() {
+            push(&mut history, &["DP-1"], &[w]);
+        }
+
+        assert_eq!(history.prev(&monitors), Some(names(&["b"])));
+        assert_eq!(history.prev(&monitors), Some(names(&["a"])));
+        push(&mut history, &["DP-1"], &["d"]);
+
+        // Nothing was dropped, and we are at the newest entry again.
+        assert_eq!(history.current(&monitors), Some(names(&["d"])));
+        assert_eq!(history.next(&monitors), None);
+        assert_eq!(history.prev(&monitors), Some(names(&["c"])));
+        assert_eq!(history.prev(&monitors), Some(names(&["b"])));
+    }
+
+    #[test]
+    fn prev_with_unequal_histories() {
+        let mut history = history();
+        push(&mut history, &["DP-1"], &["a"]);
+        push(&mut history, &["DP-1"], &["b"]);
+        push(&mut history, &["DP-1", "HDMI-1"], &["c", "x"]);
+        push(&mut history, &["DP-1", "HDMI-1"], &["d", "y"]);
+
+        // HDMI-1 stops at its oldest wallpaper.
+        let monitors = names(&["DP-1", "HDMI-1"]);
+        assert_eq!(history.prev(&monitors), Some(names(&["c", "x"])));
+        assert_eq!(history.prev(&monitors), Some(names(&["b", "x"])));
+        assert_eq!(history.prev(&monitors), Some(names(&["a", "x"])));
+        assert_eq!(history.prev(&monitors), None);
+
+        // eDP-1 was plugged since, it keeps what the first monitor shows now.
+        let mut history = history_of(&[("DP-1", &["a", "b"])]);
+        let monitors = names(&["eDP-1", "DP-1"]);
+        assert_eq!(history.prev(&monitors), Some(names(&["b", "a"])));
+    }
+}
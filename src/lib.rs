@@ -0,0 +1,37 @@
+// Rotates wallpapers, plays videos and gifs and downloads pictures, on X11 and Wayland desktops.
+// The wallpaper binary is a command line over this:
+//
+//     let config = Config::builder().dir("~/Pictures").interval(300).build()?;
+//     let mut rotator = Rotator::new(config)?;
+//     let requests = rotator.requests();
+//     std::thread::spawn(move || rotator.run().map_err(|e| e.to_string()));
+//     // Command::Next, Command::Quit... go through `requests`.
+pub mod behaviour;
+pub mod cache;
+pub mod cancel;
+pub mod compose;
+pub mod config;
+pub mod dependency;
+pub mod detect;
+pub mod error;
+pub mod function;
+pub mod history;
+pub mod log;
+pub mod monitor;
+pub mod playlist;
+pub mod runner;
+pub mod tasker;
+pub mod x11;
+
+pub use behaviour::download::{download, download_from, Pic, Report, Wallpaper};
+pub use behaviour::rotation::{Clock, Hotplug, Rotator};
+pub use cancel::Cancel;
+pub use config::{Config, ConfigBuilder, Settings};
+pub use error::{Error, Result};
+pub use function::{De, Previous, ScaleMode};
+pub use log::Log;
+pub use monitor::Monitor;
+pub use playlist::Order;
+pub use runner::{CommandRunner, FakeRunner, SystemRunner};
+pub use tasker::control::{Command, Request};
+
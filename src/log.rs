@@ -0,0 +1,35 @@
+use std::fmt;
+use std::sync::Arc;
+
+// Where the library tells what it did or got past (a wallpaper the backend refused, a new
+// monitor layout...). Nothing is printed unless the caller hooks something in.
+#[derive(Clone, Default)]
+pub struct Log(Option<Hook>);
+
+type Hook = Arc<dyn Fn(&str) + Send + Sync>;
+
+impl Log {
+    pub fn new<F>(hook: F) -> Log
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        Log(Some(Arc::new(hook)))
+    }
+
+    // Drops every message.
+    pub fn silent() -> Log {
+        Log(None)
+    }
+
+    pub fn message<M: fmt::Display>(&self, message: M) {
+        if let Some(hook) = &self.0 {
+            hook(&message.to_string());
+        }
+    }
+}
+
+impl fmt::Debug for Log {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Log({})", if self.0.is_some() { "hooked" } else { "silent" })
+    }
+}
@@ -1,186 +1,269 @@
-mod behaviour;
-mod tasker;
-mod function;
-
-use clap::{App, Arg, SubCommand};
-use rand::Rng;
+use clap::{App, AppSettings, Arg, SubCommand};
 use std::env;
-use std::path::Path;
-use std::error::Error;
-use std::io::ErrorKind;
-use std::process::Command;
-use std::thread::spawn;
-use std::{fs, thread, time};
-use function::{get_resolution, check_application, get_random_file, get_de};
-
-use crate::behaviour::download::download;
-use crate::tasker::shutdown::ShutdownSignal;
-
-#[macro_export]
-macro_rules! fatal {
-    ($($tt: tt)*) => {
-        use std::io::Write;
-        writeln!(&mut ::std::io::stderr(), $($tt)*).unwrap();        
-        ::std::process::exit(1);
-    };
-}
-
+use std::thread::{self, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+use std::fs;
+
+use wallpaper::cache;
+use wallpaper::behaviour::rotation::wallpaper_dirs;
+use wallpaper::config;
+use wallpaper::detect::{detect, BACKENDS};
+use wallpaper::dependency::{self, Dependency, FFMPEG, FFPROBE, IMAGEMAGICK, XRANDR};
+use wallpaper::function::{get_de, is_wayland, SCALE_MODES};
+use wallpaper::monitor::get_monitors;
+use wallpaper::playlist::ORDERS;
+use wallpaper::tasker::control::{self, listen, Command as ControlCommand};
+use wallpaper::tasker::instance;
+use wallpaper::tasker::shutdown::ShutdownSignal;
+use wallpaper::{download, Cancel, Config, Error, Log, Result, Rotator, Settings, SystemRunner};
+use nix::sys::signal::{kill, SIGTERM};
+use nix::unistd::getpid;
+
+// How long the rotation and a download get to stop before we quit anyway.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+// Exit status when the rotation didn't stop within SHUTDOWN_TIMEOUT, next to the ones of
+// Error::exit_code.
+const EXIT_STUCK: i32 = 9;
+
+// What this invocation does, the rotation itself is set up by `config`.
 #[derive(Debug, Clone)]
-pub struct Params {
-    dir: String,
-    is_video: bool,
-    is_gif: bool,
-    fps: u16,
+struct Params {
+    config: Config,
     is_download: bool,
-    file: Option<String>,
-    compress_dir: Option<String>,
     download_empty: bool,
-    resolution: Option<Vec<String>>,
-    download_sfw: bool,
     only_download: bool,
-    interval: i64,
-    proxy: Option<String>,
+    is_doctor: bool,
+    is_layout: bool,
+    is_config_show: bool,
+    // ls, clear or size.
+    cache: Option<String>,
+    control: Option<ControlCommand>,
+    replace: bool,
+    // What to hand to an instance that is already running.
+    forward: Vec<ControlCommand>,
+    // Options given that a running instance can't take.
+    not_forwarded: Vec<String>,
 }
 
-impl Params {
-    fn new(
-        dir: String,
-        is_video: bool,
-        is_gif: bool,
-        fps: u16,
-        file: Option<String>,
-        is_download: bool,
-        compress_dir: Option<String>,
-        download_empty: bool,
-        resolution: Option<Vec<String>>,
-        download_sfw: bool,
-        only_download: bool,
-        interval: i64,
-        proxy: Option<String>,
-    ) -> Params {
-        Params {
-            dir,
-            is_video,
-            is_gif,
-            fps,
-            is_download,
-            file,
-            compress_dir,
-            download_empty,
-            resolution,
-            download_sfw,
-            only_download,
-            interval,
-            proxy,
+fn main() {
+    let params:Params = get_params().unwrap_or_else(|e| fail(e));
+    if params.is_doctor {
+        doctor(&params);
+        return;
+    }
+    if params.is_layout {
+        layout(&params);
+        return;
+    }
+    if params.is_config_show {
+        show_config(&params);
+        return;
+    }
+    if let Some(c) = &params.cache {
+        if let Err(e) = manage_cache(c) {
+            fail(e);
         }
+        return;
+    }
+    if let Some(c) = &params.control {
+        match control::send(c) {
+            Ok(answer) => print!("{}", answer),
+            Err(e) => fail(e),
+        }
+        return;
+    }
+    if let Err(e) = check_dependency(&params) {
+        fail(e);
     }
-}
 
-fn main() {
-    let params:Params = match get_params() {
-        Ok(p) => p,
-        Err(e) => {
-            fatal!("{}", e);
+    // Nothing else to wait for, the exit status is the one of the download.
+    if params.only_download {
+        match fetch(&params.config, params.download_empty, &Cancel::new()) {
+            Ok(()) => return,
+            Err(e) => fail(e),
         }
+    }
+
+    let _instance = match instance::lock() {
+        Ok(Some(i)) => i,
+        Ok(None) if params.replace => instance::replace().unwrap_or_else(|e| fail(e)),
+        Ok(None) => {
+            let forwarded = forward(&params).and_then(|()| match params.is_download {
+                true => fetch(&params.config, params.download_empty, &Cancel::new()),
+                false => Ok(()),
+            });
+            if let Err(e) = forwarded {
+                fail(e);
+            }
+            return;
+        }
+        Err(e) => fail(e),
     };
-    check_dependency(&params);
 
-    let signal = ShutdownSignal::new();
-    let params_c = params.clone();
+    // The backend lives as long as the rotation, so it can keep its own state (e.g. swaybg processes).
+    let log = Log::new(|m| println!("{}", m));
+    let cancel = Cancel::new();
+    let mut rotator = Rotator::new(params.config.clone())
+        .unwrap_or_else(|e| fail(e))
+        .with_log(log.clone())
+        .with_cancel(cancel.clone());
 
+    let mut signal = ShutdownSignal::new();
 
+    let mut downloading = None;
     if params.is_download {
         let params_c = params.clone();
-        let handle = spawn( move || {
-            download(&params_c) }
-        );
-
-        if params.only_download {
-            handle.join().unwrap();
-            std::process::exit(1);
-        }
+        let cancel_c = cancel.clone();
+        // The wallpapers already there keep rotating when it fails.
+        let handle = spawn(move || {
+            if let Err(e) = fetch(&params_c.config, params_c.download_empty, &cancel_c) {
+                eprintln!("Download failed:{}", e);
+            }
+        });
+        downloading = Some(handle);
     }
 
-    spawn(move || {
-        loop {
-            if params.is_video {
-                video(&params);
-            } else if params.is_gif {
-                gif(&params);
-            } else {
-                image(&params);
-            }
-        }
+    // Commands come from the socket and from signals.
+    if let Err(e) = listen(rotator.requests(), log) {
+        println!("Control socket unavailable:{}", e);
+    }
+    signal.route(rotator.requests());
+
+    // A rotation that failed or was told to quit through the socket takes the same way out as a
+    // SIGTERM, shutdown reports how it ended.
+    let rotation = spawn(move || {
+        let result = rotator.run();
+        let _ = kill(getpid(), SIGTERM);
+        result
     });
 
     signal.at_exit(move |_| {
-        if params_c.is_video {
-            fs::remove_dir_all(params_c.compress_dir.clone().unwrap()).unwrap();
-        }
-        std::process::abort();
-   });
+        shutdown(rotation, downloading, cancel);
+    });
 }
 
-fn video(params: &Params) {
-    let interval = time::Duration::from_millis(1);
-    let dir = params.compress_dir.clone().unwrap();
-    println!("Start processing video files...");
-    Command::new("ffmpeg")
-        .arg("-y")
-        .arg("-ss")
-        .arg("00:00")
-        .arg("-i")
-        .arg(params.file.clone().unwrap())
-        .arg(format!("{}/filename%09d.jpg", &dir))
-        .output()
-        .expect("sh command failed to start");
-    println!("Ok");
-
-    loop_dir(interval, &dir);
+// The rotation was asked to stop already, a download stops after the picture it is fetching
+// and ffmpeg or convert are killed.
+// The exit status is the one of the rotation, EXIT_STUCK when it didn't stop in time.
+fn shutdown(rotation: JoinHandle<Result<()>>, downloading: Option<JoinHandle<()>>, cancel: Cancel) {
+    cancel.cancel();
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    let result = match finished(&rotation, deadline) {
+        true => Some(rotation.join().unwrap_or(Ok(()))),
+        false => None,
+    };
+    if let Some(handle) = downloading.filter(|h| result.is_some() && finished(h, deadline)) {
+        let _ = handle.join();
+    }
+
+    if let Ok(path) = control::socket_path() {
+        let _ = fs::remove_file(path);
+    }
+    match result {
+        Some(Ok(())) => std::process::exit(0),
+        Some(Err(e)) => fail(e),
+        None => std::process::exit(EXIT_STUCK),
+    }
 }
 
-fn gif(params: &Params) {
-    let mill =  1000 / params.fps;
-    let interval = time::Duration::from_millis(mill.into());
-    let dir = params.compress_dir.clone().unwrap();
-    println!("Start processing gif files...");
-    Command::new("convert")
-        .arg(params.file.clone().unwrap())
-        .arg("-coalesce")
-        .arg(format!("{}/filename%09d.jpg", &dir))
-        .output()
-        .expect("sh command failed to start");
-    println!("Ok");
-
-    loop_dir(interval, &dir);
+fn finished<T>(handle: &JoinHandle<T>, deadline: Instant) -> bool {
+    while !handle.is_finished() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    if !handle.is_finished() {
+        println!("Still busy after {}s, quitting anyway", SHUTDOWN_TIMEOUT.as_secs());
+    }
+    handle.is_finished()
 }
 
-fn image(params: &Params) {
-    let ten_millis = time::Duration::from_millis((params.interval * 1000) as u64);
-    let mut rand_images:Vec<String> = vec!();
-    let resolutions = params.resolution.clone();
-    for r in resolutions.unwrap().iter() {
-        let resolution_dir = format!("{}{}", &params.dir, r);
-        if let Ok(_) = fs::read_dir(&resolution_dir) {
-            rand_images.push(get_random_file(&resolution_dir));
-        } else {
-            // default dir
-            let default_dir = format!("{}", &params.dir);
-            if let Err(e) = fs::read_dir(&default_dir) {
-                fatal!("Directory {} takes error:{}", &default_dir, e);
-            } else {
-                rand_images.push(get_random_file(&default_dir));
+// Pictures that couldn't be fetched are only told, the others are saved.
+fn fetch(config: &Config, empty_dir: bool, cancel: &Cancel) -> Result<()> {
+    println!("Starting...");
+    let report = download(config, empty_dir, cancel)?;
+    for e in report.failed.iter() {
+        println!("{}", e);
+    }
+    println!("Gets {} wallpapers", report.saved);
+
+    Ok(())
+}
+
+fn fail(e: Error) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(e.exit_code());
+}
+
+// Hands the options of this invocation to the running instance.
+fn forward(params: &Params) -> Result<()> {
+    let pid = instance::running_pid().map(|p| p.to_string()).unwrap_or_else(|| "?".to_string());
+    if !params.not_forwarded.is_empty() {
+        Err(Error::Instance(format!(
+            "wallpaper is already running (pid {}), {} can't be changed without --replace",
+            pid,
+            params.not_forwarded.join(", ")
+        )))?
+    }
+    if params.forward.is_empty() && !params.is_download {
+        Err(Error::Instance(format!("wallpaper is already running (pid {}), use --replace to restart it", pid)))?
+    }
+
+    for c in params.forward.iter() {
+        control::send(c)?;
+    }
+
+    Ok(())
+}
+
+// Prints the merged settings in the format of the config file.
+fn show_config(params: &Params) {
+    println!("# {}", config::path().display());
+    match params.config.profile() {
+        Some((p, false)) => println!("# profile {}", p),
+        Some((p, true)) => println!("# profile {} (matched)", p),
+        None => {}
+    }
+    print!("{}", toml::to_string(&params.config.settings()).unwrap());
+}
+
+fn manage_cache(command: &str) -> Result<()> {
+    let dir = cache::frames_dir();
+    match command {
+        "ls" => {
+            for e in cache::entries(&dir)?.iter() {
+                println!("{} {} frames, {}: {}", e.key, e.frames, human_size(e.size), e.source);
             }
         }
+        // The frames a running wallpaper plays are kept.
+        "clear" => println!("Freed {}", human_size(cache::clear(&dir)?)),
+        _ => println!("{} in {}", human_size(cache::size(&dir)), dir.display()),
     }
 
-    let de = get_de();
-    de.set_wallpaper(rand_images);
+    Ok(())
+}
 
-    thread::sleep(ten_millis);
+fn human_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    for unit in ["KiB", "MiB", "GiB"].iter() {
+        if size < 1024.0 {
+            return format!("{:.1} {}", size, unit);
+        }
+        size /= 1024.0;
+    }
+
+    format!("{:.1} TiB", size)
 }
 
-fn get_params() -> Result<Params, Box<dyn Error>> {
+fn layout(params: &Params) {
+    let dirs = wallpaper_dirs(&params.config).unwrap_or_else(|e| fail(e));
+    for (monitor, resolution, dir) in dirs.iter() {
+        println!("{} ({}): {}", monitor, resolution, dir);
+    }
+}
+
+fn get_params() -> Result<Params> {
     let matches = App::new("Wallpaper")
         .version("1.0")
         .help_message("help").version_message("version")
@@ -197,13 +280,9 @@ fn get_params() -> Result<Params, Box<dyn Error>> {
             Arg::with_name("interval")
                 .short("i")
                 .long("interval")
-                .validator(|v| {
-                    match v.parse::<i32>() {
-                        Ok(_) => {return Ok(())},
-                        Err(_) => {
-                            return Err("Please enter the correct number of interval seconds".to_string());
-                        }
-                    }
+                .validator(|v| match v.parse::<i32>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("Please enter the correct number of interval seconds".to_string()),
                 })
                 .help("Interval second to switch wallpapers,default is 60")
                 .takes_value(true),
@@ -214,6 +293,133 @@ fn get_params() -> Result<Params, Box<dyn Error>> {
             .help("Set resolution for the downloading or wallpaper setting,Multiple resolutions are separated by commas")
             .takes_value(true)
             .empty_values(false)
+        ).arg(
+            Arg::with_name("backend")
+            .short("b")
+            .long("backend")
+            .help("Force the backend used to set wallpapers instead of detecting the desktop environment")
+            .possible_values(&BACKENDS)
+            .takes_value(true)
+        ).arg(
+            Arg::with_name("mode")
+            .short("m")
+            .long("mode")
+            .help("How wallpapers are scaled to the monitor")
+            .possible_values(&SCALE_MODES)
+            .default_value("fill")
+            .takes_value(true)
+        ).arg(
+            Arg::with_name("order")
+            .short("o")
+            .long("order")
+            .help("Order of the wallpapers, shuffle shows every file once before repeating")
+            .possible_values(&ORDERS)
+            .default_value("shuffle")
+            .takes_value(true)
+        ).arg(
+            Arg::with_name("replace")
+            .long("replace")
+            .help("Stop the wallpaper already running and take its place")
+        ).arg(
+            Arg::with_name("profile")
+            .long("profile")
+            .help("Use the [profile.<name>] section of the config file")
+            .takes_value(true)
+        ).arg(
+            Arg::with_name("restore_on_exit")
+            .long("restore-on-exit")
+            .help("Put back the wallpaper shown before starting when quitting")
+        ).arg(
+            Arg::with_name("speed")
+            .long("speed")
+            .help("How fast videos and gifs play, 2 is twice as fast, default is 1")
+            .validator(is_valid_speed)
+            .takes_value(true)
+        ).arg(
+            Arg::with_name("loop_count")
+            .long("loop-count")
+            .help("Stop videos and gifs on their last frame after this many loops, default is 0 for forever")
+            .validator(|v| v.parse::<u32>().map(|_c| ()).map_err(|_e| "Please enter the number of loops".to_string()))
+            .takes_value(true)
+        )
+        .subcommand(
+            SubCommand::with_name("doctor").help_message("help").version_message("version")
+            .about("Show the detected desktop environment and why it was chosen")
+        )
+        .subcommand(
+            SubCommand::with_name("config").help_message("help").version_message("version")
+            .about("Inspect the configuration")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("show").help_message("help").version_message("version")
+                .about("Print the settings merged from the config file, the environment and the command line")
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("cache").help_message("help").version_message("version")
+            .about("Manage the frames kept for videos and gifs")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("ls").help_message("help").version_message("version")
+                .about("List the videos and gifs with frames in the cache")
+            )
+            .subcommand(
+                SubCommand::with_name("clear").help_message("help").version_message("version")
+                .about("Delete every frame, they are extracted again when played")
+            )
+            .subcommand(
+                SubCommand::with_name("size").help_message("help").version_message("version")
+                .about("Show the disk space used by the cache")
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("layout").help_message("help").version_message("version")
+            .about("Show which directory feeds which monitor")
+        )
+        .subcommand(
+            SubCommand::with_name("next").help_message("help").version_message("version")
+            .about("Show the next wallpaper in the running wallpaper")
+        )
+        .subcommand(
+            SubCommand::with_name("prev").help_message("help").version_message("version")
+            .about("Go back to the previous wallpaper in the running wallpaper")
+        )
+        .subcommand(
+            SubCommand::with_name("history").help_message("help").version_message("version")
+            .about("List the wallpapers shown on every monitor")
+        )
+        .subcommand(
+            SubCommand::with_name("pause").help_message("help").version_message("version")
+            .about("Keep the current wallpaper in the running wallpaper")
+        )
+        .subcommand(
+            SubCommand::with_name("resume").help_message("help").version_message("version")
+            .about("Rotate again after a pause")
+        )
+        .subcommand(
+            SubCommand::with_name("toggle").help_message("help").version_message("version")
+            .about("Pause or resume the running wallpaper")
+        )
+        .subcommand(
+            SubCommand::with_name("set").help_message("help").version_message("version")
+            .about("Show a picture on every monitor, or rotate from another directory").arg(
+                Arg::with_name("path")
+                    .help("Picture or directory")
+                    .required(true)
+                    .takes_value(true),
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("status").help_message("help").version_message("version")
+            .about("Show what the running wallpaper is doing")
+        )
+        .subcommand(
+            SubCommand::with_name("reload").help_message("help").version_message("version")
+            .about("Read the wallpaper directory again and change the wallpaper")
+        )
+        .subcommand(
+            SubCommand::with_name("quit").help_message("help").version_message("version")
+            .about("Stop the running wallpaper")
         )
         .subcommand(
             SubCommand::with_name("video").help_message("help").version_message("version")
@@ -241,8 +447,7 @@ fn get_params() -> Result<Params, Box<dyn Error>> {
                 Arg::with_name("fps")
                     .short("p")
                     .long("fps")
-                    .default_value("10")
-                    .help("Gif FPS")
+                    .help("Gif FPS, the delays of its frames by default")
                     .validator(is_valid_fps)
                     .takes_value(true),
                 ),
@@ -303,124 +508,115 @@ fn get_params() -> Result<Params, Box<dyn Error>> {
                 .subcommand_matches("gif")
                 .unwrap()
                 .value_of("fps")
-                .unwrap()
-                .to_owned().parse::<u16>()
-                .unwrap();
+                .map(|f| f.parse::<u16>().unwrap())
+                .unwrap_or(0);
     }
 
-    let mut compress_dir = None;
-    if is_video || is_gif {
-        let rand_string: String = gen_rand_string();
-        let mut path = env::temp_dir();
-        path.push(rand_string);
-        compress_dir = Some(path.as_path().to_str().unwrap().to_owned());
-
-        fs::create_dir(&path).unwrap();
-    }
-
-    let mut default_dir = "".to_owned();
-    for (key, val) in env::vars() {
-        if key == "HOME".to_owned() {
-            default_dir = format!("{}{}", default_dir, val);
-        }
-    }
-    default_dir.push_str("/.wallpaper/");
     let mut download_empty = false;
-    let mut download_sfw = false;
     let mut only_download = false;
-    let mut resolution = None;
-    let mut proxy = None;
-    if let Ok(r) = get_resolution() {
-        resolution = Some(r);
-    }
-
-    match matches
-        .value_of("resolution") {
-        Some(r) => {
-            let r_v= r.split(",").map(|v| v.to_owned()).collect::<Vec<String>>();
-            resolution = Some(r_v)
-        },
-        None => {},
-    };
+    let monitors = get_monitors().unwrap_or_default();
 
     let is_download = matches.is_present("download");
     if is_download {
         download_empty = matches.subcommand_matches("download")
                         .unwrap()
                         .is_present("empty");
-        download_sfw = matches.subcommand_matches("download")
-                        .unwrap()
-                        .is_present("sfw");
         only_download = matches.subcommand_matches("download")
                         .unwrap()
                         .is_present("only_download");
-        
-        proxy = matches.subcommand_matches("download").unwrap()
-                        .value_of("proxy").map(|v| v.to_owned());
     } 
-    
-    let interval = matches
-        .value_of("interval")
-        .unwrap_or("60")
-        .to_owned().parse::<i64>().unwrap();
-    let mut dir = matches
-        .value_of("directory")
-        .unwrap_or(&default_dir)
-        .to_owned();
-    if !dir.ends_with("/") {
-        dir.push_str("/");
-    }
-
-    if !is_video && !is_gif && resolution == None {
-        fatal!("Please specify the resolution.");        
-    }
-
-    // check dir
-    let path = Path::new(&dir);
-    let metadata;
-    match path.metadata() {
-        Ok(m) => {
-            metadata = m;
-        },
-        Err(e) => {
-            if e.kind() == ErrorKind::NotFound {
-                // Try to create a new directory
-                match std::fs::create_dir_all(path) {
-                    Ok(_r) => {
-                        metadata = path.metadata().unwrap();
-                    },
-                    Err(e) => {
-                        fatal!("{}:{}", &dir, e);
-                    },
-                }   
-            } else {
-                fatal!("{}:{}", &dir, e);       
-            }
+
+    // Mode and order have defaults for the help, only the ones typed count over the config.
+    let explicit = |name: &str| match matches.occurrences_of(name) {
+        0 => None,
+        _ => matches.value_of(name).map(|v| v.to_owned()),
+    };
+    let download_m = matches.subcommand_matches("download");
+    let cli = Settings {
+        dir: explicit("directory"),
+        interval: explicit("interval").map(|v| v.parse().unwrap()),
+        resolution: explicit("resolution").map(|r| r.split(",").map(|v| v.to_owned()).collect()),
+        backend: explicit("backend"),
+        mode: explicit("mode"),
+        order: explicit("order"),
+        sources: None,
+        proxy: download_m.and_then(|m| m.value_of("proxy")).map(|v| v.to_owned()),
+        sfw: download_m.filter(|m| m.is_present("sfw")).map(|_m| true),
+        restore_on_exit: Some(true).filter(|_r| matches.is_present("restore_on_exit")),
+    };
+    let profile = explicit("profile").or_else(|| env::var("WALLPAPER_PROFILE").ok().filter(|p| !p.is_empty()));
+    let control = match matches.subcommand() {
+        // The running wallpaper may have another working directory.
+        ("set", Some(sub_m)) => {
+            let path = sub_m.value_of("path").unwrap();
+            let path = fs::canonicalize(path).map_err(|e| Error::io(path, e))?;
+            Some(ControlCommand::Set(path.to_string_lossy().into_owned()))
         }
-    }
+        (name, _) => ControlCommand::from_line(name),
+    };
 
-    if !metadata.is_dir() {
-        fatal!("{} is not a dir", &dir);
-    }; 
+    let mut builder = Config::builder().settings(cli).monitors(monitors);
+    if is_video {
+        builder = builder.video(file.as_ref().unwrap());
+    }
+    if is_gif {
+        builder = builder.gif(file.as_ref().unwrap(), fps);
+    }
+    if let Some(speed) = explicit("speed") {
+        builder = builder.speed(speed.parse().unwrap());
+    }
+    if let Some(count) = explicit("loop_count") {
+        builder = builder.loop_count(count.parse().unwrap());
+    }
+    let config = match builder.clone().config_file(profile.as_deref()).build() {
+        Ok(c) => c,
+        // A broken config must not keep us from reaching the running instance.
+        Err(_e) if control.is_some() => builder.build()?,
+        Err(e) => return Err(e),
+    };
 
-    Ok(Params::new(
-        dir,
-        is_video,
-        is_gif,
-        fps,
-        file,
+    let mut forward = vec!();
+    if matches.occurrences_of("directory") > 0 {
+        forward.push(ControlCommand::Set(absolute(config.dir())));
+    }
+    if let Some(interval) = explicit("interval") {
+        forward.push(ControlCommand::Interval(interval.parse().unwrap()));
+    }
+    if let Some(speed) = explicit("speed") {
+        forward.push(ControlCommand::Speed(speed.parse().unwrap()));
+    }
+    if let Some(count) = explicit("loop_count") {
+        forward.push(ControlCommand::LoopCount(count.parse().unwrap()));
+    }
+    if is_gif {
+        forward.push(ControlCommand::Gif(fps, absolute(file.as_ref().unwrap())));
+    }
+    if is_video {
+        forward.push(ControlCommand::Video(absolute(file.as_ref().unwrap())));
+    }
+    let not_forwarded = ["resolution", "backend", "mode", "order"]
+        .iter()
+        .filter(|o| matches.occurrences_of(o) > 0)
+        .map(|o| format!("--{}", o))
+        .collect::<Vec<String>>();
+
+    Ok(Params {
+        config,
         is_download,
-        compress_dir,
         download_empty,
-        resolution,
-        download_sfw,
         only_download,
-        interval,
-        proxy,
-    ))
+        is_doctor: matches.is_present("doctor"),
+        is_layout: matches.is_present("layout"),
+        is_config_show: matches.subcommand_matches("config").map(|m| m.is_present("show")).unwrap_or(false),
+        cache: matches.subcommand_matches("cache").and_then(|m| m.subcommand_name()).map(|c| c.to_owned()),
+        control,
+        replace: matches.is_present("replace"),
+        forward,
+        not_forwarded,
+    })
 }
 
-fn is_mp4(file: String) -> Result<(), String> {
+fn is_mp4(file: String) -> std::result::Result<(), String> {
     if !file.ends_with(".mp4") {
         return Err(String::from("Video needs to be .mp4 suffix"));
     }
@@ -435,7 +631,7 @@ fn is_mp4(file: String) -> Result<(), String> {
     Ok(())
 }
 
-fn is_gif(file: String) -> Result<(), String> {
+fn is_gif(file: String) -> std::result::Result<(), String> {
     if !file.ends_with(".gif") {
         return Err(String::from("Gif needs to be .gif suffix"));
     }
@@ -450,79 +646,102 @@ fn is_gif(file: String) -> Result<(), String> {
     Ok(())
 }
 
-fn is_valid_fps(fps: String) -> Result<(), String> {
+fn is_valid_fps(fps: String) -> std::result::Result<(), String> {
     let fps = match fps.parse::<u16>() {
         Ok(f) => f,
         Err(_e) => {
             return Err(String::from("The range of FPS is an integer from 1 to 1000"));
         }
     };
-    if fps < 1 || fps > 1000 {
+    if !(1..=1000).contains(&fps) {
         return Err(String::from("The range of FPS is an integer from 1 to 1000"));
     }
 
     Ok(())
 }
 
-fn check_dependency(params: &Params) {
-    let mut dependencies: Vec<&str> = vec![];
-    let de = get_de();
-    let wallpaper_d = de.wallpaper_dependencies();
+fn is_valid_speed(speed: String) -> std::result::Result<(), String> {
+    match speed.parse::<f64>() {
+        Ok(s) if s.is_finite() && s > 0.0 => Ok(()),
+        _ => Err(String::from("The speed is a positive number, 0.5 plays at half speed")),
+    }
+}
 
+// Everything missing is reported at once.
+fn check_dependency(params: &Params) -> Result<()> {
+    let mut dependencies: Vec<Dependency> = vec![];
     if !params.only_download {
-        dependencies.append(&mut wallpaper_d.iter().map(|s| s as &str).collect::<Vec<&str>>());
+        dependencies.append(&mut wallpaper_dependencies(&params.config)?);
     }
 
-    if params.is_video {
-        dependencies.append(&mut vec![
-            "ffmpeg", "convert", "xdg-open", "bash", "sed",
-        ]);
+    if params.config.is_video() {
+        dependencies.append(&mut vec!(FFMPEG, FFPROBE));
     }
 
-    if params.is_gif {
-        dependencies.append(&mut vec![
-            "convert", "bash",
-        ]);
+    if params.config.is_gif() {
+        dependencies.push(IMAGEMAGICK);
     }
 
-    for i in dependencies.iter() {
-        check_application(i);
-    }
+    dependency::check(&dependencies, &SystemRunner)
 }
 
-fn gen_rand_string() -> String {
-    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
-                            abcdefghijklmnopqrstuvwxyz\
-                            0123456789";
-    const PASSWORD_LEN: usize = 20;
-    let mut rng = rand::thread_rng();
-
-    (0..PASSWORD_LEN)
-        .map(|_| {
-            let idx = rng.gen_range(0, CHARSET.len());
-            CHARSET[idx] as char
-        })
-        .collect()
+// Monitors are read with xrandr on X11.
+fn wallpaper_dependencies(config: &Config) -> Result<Vec<Dependency>> {
+    let mut dependencies = get_de(config.backend())?.wallpaper_dependencies();
+    if !is_wayland() {
+        dependencies.insert(0, XRANDR);
+    }
+
+    Ok(dependencies)
 }
 
-fn loop_dir(interval: core::time::Duration, dir: &str) {
-    let mut v: Vec<_> = fs::read_dir(dir)
-        .unwrap()
-        .map(|r| r.unwrap())
-        .collect();
-    v.sort_by_key(|dir| dir.path());
-    let count = v.len();
-
-    let mut i = 0;
-    let de = get_de();
-    while i < count {
-        let pic_path = format!("{}", &v[i].path().display());
-        de.set_wallpaper(vec!(pic_path));
-        i += 1;
-        if i == count - 1 {
-            i = 0;
+fn doctor(params: &Params) {
+    let config = &params.config;
+    let detection = detect(config.backend());
+    let or_unset = |v: &Option<String>| v.clone().unwrap_or_else(|| "(unset)".to_string());
+
+    println!("XDG_CURRENT_DESKTOP: {}", detection.desktops.join(":"));
+    println!("DESKTOP_SESSION: {}", or_unset(&detection.session));
+    println!("WAYLAND_DISPLAY: {}", or_unset(&detection.wayland));
+    println!("Running daemons: {}", detection.daemons.join(", "));
+    println!("Backend: {} ({})", detection.backend.name(), detection.reason);
+    println!("Scale mode: {}", config.mode().name());
+    println!("Order: {}", config.order().name());
+    match wallpaper_dependencies(config) {
+        Ok(mut dependencies) => {
+            dependencies.append(&mut vec!(FFMPEG, FFPROBE, IMAGEMAGICK));
+            for d in dependencies.iter() {
+                match d.probe(&SystemRunner) {
+                    Ok(path) => println!("Dependency {}: {}", d.name, path.display()),
+                    Err(e) => println!("Dependency {}", e),
+                }
+            }
         }
-
-        thread::sleep(interval);
+        Err(e) => println!("Dependencies: {}", e),
     }
+    if config.monitors().is_empty() {
+        println!("Monitors: not detected");
+    }
+    for m in config.monitors().iter() {
+        println!(
+            "Monitor {}: {} at {:+}{:+}, {}, {}mm x {}mm{}{}",
+            m.name,
+            m.resolution(),
+            m.x,
+            m.y,
+            m.rotation.name(),
+            m.width_mm,
+            m.height_mm,
+            if m.primary { ", primary" } else { "" },
+            if m.serial.is_empty() { String::new() } else { format!(", serial {}", m.serial) }
+        );
+    }
+    println!("Resolutions: {}", config.resolutions().join(", "));
+}
+
+// The running instance may have another working directory.
+fn absolute(path: &str) -> String {
+    let path = fs::canonicalize(path).unwrap_or_else(|_e| env::current_dir().unwrap_or_default().join(path));
+
+    path.to_string_lossy().into_owned()
 }
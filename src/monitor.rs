@@ -0,0 +1,543 @@
+use crate::error::{Error, Result};
+use crate::function::is_wayland;
+use crate::runner::{CommandRunner, SystemRunner};
+use regex::Regex;
+use std::fs::{read, read_dir};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    Normal,
+    Left,
+    Inverted,
+    Right,
+}
+
+impl Rotation {
+    pub fn from_name(name: &str) -> Option<Rotation> {
+        match name {
+            "normal" | "0" => Some(Rotation::Normal),
+            "left" | "270" => Some(Rotation::Left),
+            "inverted" | "180" => Some(Rotation::Inverted),
+            "right" | "90" => Some(Rotation::Right),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rotation::Normal => "normal",
+            Rotation::Left => "left",
+            Rotation::Inverted => "inverted",
+            Rotation::Right => "right",
+        }
+    }
+}
+
+// Sizes are the ones the monitor shows, i.e. already swapped for a rotated monitor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Monitor {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub x: i32,
+    pub y: i32,
+    pub rotation: Rotation,
+    pub primary: bool,
+    pub width_mm: u32,
+    pub height_mm: u32,
+    // From the EDID, empty when unknown.
+    pub serial: String,
+}
+
+impl Monitor {
+    pub fn new(name: &str, width: u32, height: u32, x: i32, y: i32) -> Monitor {
+        Monitor {
+            name: name.to_string(),
+            width,
+            height,
+            x,
+            y,
+            rotation: Rotation::Normal,
+            primary: false,
+            width_mm: 0,
+            height_mm: 0,
+            serial: String::new(),
+        }
+    }
+
+    pub fn resolution(&self) -> String {
+        format!("{}x{}", self.width, self.height)
+    }
+}
+
+pub fn get_monitors() -> Result<Vec<Monitor>> {
+    get_monitors_with(&SystemRunner)
+}
+
+pub fn get_monitors_with(runner: &dyn CommandRunner) -> Result<Vec<Monitor>> {
+    let monitors = if is_wayland() {
+        get_wayland_monitors(runner)?
+    } else {
+        get_xrandr_monitors(runner)?
+    };
+
+    if monitors.is_empty() {
+        Err(Error::backend("Monitors", "none detected"))?
+    }
+
+    Ok(monitors)
+}
+
+// --listactivemonitors has the geometry of every monitor in use, --query adds the rotation.
+pub fn get_xrandr_monitors(runner: &dyn CommandRunner) -> Result<Vec<Monitor>> {
+    let active = runner.run("xrandr", &["--listactivemonitors"])?;
+    let mut monitors = parse_active_monitors(&active);
+
+    let query = runner.run("xrandr", &["--query"])?;
+    let rotations = parse_query_rotations(&query);
+    let serials = get_drm_serials();
+    for m in monitors.iter_mut() {
+        if let Some((_, r)) = rotations.iter().find(|(n, _)| n == &m.name) {
+            m.rotation = *r;
+        }
+        if let Some((_, s)) = serials.iter().find(|(c, _)| connector_name(c) == connector_name(&m.name)) {
+            m.serial = s.to_string();
+        }
+    }
+
+    Ok(monitors)
+}
+
+// Monitors: 2
+//  0: +*eDP-1 1920/344x1080/193+0+0  eDP-1
+//  1: +HDMI-1 1080/336x1920/597+1920+0  HDMI-1
+pub fn parse_active_monitors(output: &str) -> Vec<Monitor> {
+    let re = Regex::new(r"^\s*\d+:\s+\+?(\*?)(\S+)\s+(\d+)/(\d+)x(\d+)/(\d+)([+-]\d+)([+-]\d+)\s+(\S+)").unwrap();
+
+    output
+        .lines()
+        .filter_map(|l| re.captures(l))
+        .map(|c| Monitor {
+            // The last column is the output, the first name can be a user defined monitor (--setmonitor).
+            name: c[9].to_string(),
+            width: c[3].parse().unwrap_or(0),
+            height: c[5].parse().unwrap_or(0),
+            x: c[7].parse().unwrap_or(0),
+            y: c[8].parse().unwrap_or(0),
+            rotation: Rotation::Normal,
+            primary: &c[1] == "*",
+            width_mm: c[4].parse().unwrap_or(0),
+            height_mm: c[6].parse().unwrap_or(0),
+            serial: String::new(),
+        })
+        .collect()
+}
+
+// HDMI-1 connected 1080x1920+1920+0 left (normal left inverted right x axis y axis) 597mm x 336mm
+pub fn parse_query_rotations(output: &str) -> Vec<(String, Rotation)> {
+    let geometry = Regex::new(r"^\d+x\d+[+-]\d+[+-]\d+$").unwrap();
+    let mut rotations = vec!();
+
+    for line in output.lines() {
+        let mut tokens = line.split_whitespace();
+        let name = match (tokens.next(), tokens.next()) {
+            (Some(n), Some("connected")) => n,
+            _ => continue,
+        };
+        let mut tokens = tokens.skip_while(|t| *t == "primary");
+        if !tokens.next().map(|t| geometry.is_match(t)).unwrap_or(false) {
+            continue;
+        }
+        let rotation = tokens.next().and_then(Rotation::from_name).unwrap_or(Rotation::Normal);
+        rotations.push((name.to_string(), rotation));
+    }
+
+    rotations
+}
+
+// GNOME and KDE have neither swaymsg nor wlr-randr, XWayland still answers xrandr there.
+// Its outputs are already rotated and aren't named after connectors, so neither --query nor
+// the EDIDs would tell more than the monitors do.
+pub fn get_wayland_monitors(runner: &dyn CommandRunner) -> Result<Vec<Monitor>> {
+    match get_wlr_monitors(runner) {
+        Ok(monitors) if !monitors.is_empty() => Ok(monitors),
+        _ => Ok(parse_active_monitors(&runner.run("xrandr", &["--listactivemonitors"])?)),
+    }
+}
+
+// Sway answers through its IPC, other wlroots compositors (Hyprland, river...) through wlr-randr.
+pub fn get_wlr_monitors(runner: &dyn CommandRunner) -> Result<Vec<Monitor>> {
+    if let Ok(o) = runner.run("swaymsg", &["-t", "get_outputs", "-r"]) {
+        return parse_sway_outputs(&o);
+    }
+
+    parse_wlr_randr(&runner.run("wlr-randr", &["--json"])?)
+}
+
+pub fn parse_sway_outputs(output: &str) -> Result<Vec<Monitor>> {
+    let outputs: serde_json::Value = serde_json::from_str(output).map_err(|e| Error::backend("swaymsg", e))?;
+    let outputs = outputs.as_array().cloned().unwrap_or_default();
+
+    Ok(outputs
+        .iter()
+        .filter(|o| o["active"].as_bool().unwrap_or(false))
+        .filter_map(|o| {
+            let rotation = o["transform"].as_str().map(transform).unwrap_or(Rotation::Normal);
+            let mut m = Monitor::new(
+                o["name"].as_str()?,
+                o["current_mode"]["width"].as_u64()? as u32,
+                o["current_mode"]["height"].as_u64()? as u32,
+                o["rect"]["x"].as_i64().unwrap_or(0) as i32,
+                o["rect"]["y"].as_i64().unwrap_or(0) as i32,
+            );
+            m.rotation = rotation;
+            m.primary = o["primary"].as_bool().unwrap_or(false);
+            m.serial = serial(&o["serial"]);
+            Some(rotated(m))
+        })
+        .collect())
+}
+
+pub fn parse_wlr_randr(output: &str) -> Result<Vec<Monitor>> {
+    let outputs: serde_json::Value = serde_json::from_str(output).map_err(|e| Error::backend("wlr-randr", e))?;
+    let outputs = outputs.as_array().cloned().unwrap_or_default();
+
+    Ok(outputs
+        .iter()
+        .filter(|o| o["enabled"].as_bool().unwrap_or(false))
+        .filter_map(|o| {
+            let mode = o["modes"]
+                .as_array()?
+                .iter()
+                .find(|m| m["current"].as_bool().unwrap_or(false))?;
+            let mut m = Monitor::new(
+                o["name"].as_str()?,
+                mode["width"].as_u64()? as u32,
+                mode["height"].as_u64()? as u32,
+                o["position"]["x"].as_i64().unwrap_or(0) as i32,
+                o["position"]["y"].as_i64().unwrap_or(0) as i32,
+            );
+            m.rotation = o["transform"].as_str().map(transform).unwrap_or(Rotation::Normal);
+            m.width_mm = o["physical_size"]["width"].as_u64().unwrap_or(0) as u32;
+            m.height_mm = o["physical_size"]["height"].as_u64().unwrap_or(0) as u32;
+            m.serial = serial(&o["serial"]);
+            Some(rotated(m))
+        })
+        .collect())
+}
+
+// Sway says "Unknown" when the monitor has no serial.
+fn serial(value: &serde_json::Value) -> String {
+    match value.as_str() {
+        Some("Unknown") | None => String::new(),
+        Some(s) => s.trim().to_string(),
+    }
+}
+
+// X11 doesn't tell the serial without parsing every property, the kernel keeps the EDID of each
+// connector in /sys/class/drm/card0-HDMI-A-1/edid.
+fn get_drm_serials() -> Vec<(String, String)> {
+    let entries = match read_dir("/sys/class/drm") {
+        Ok(e) => e,
+        Err(_e) => return vec!(),
+    };
+
+    entries
+        .flatten()
+        .filter_map(|e| {
+            let name = e.file_name().into_string().ok()?;
+            let (_card, connector) = name.split_once('-')?;
+            let serial = parse_edid_serial(&read(e.path().join("edid")).ok()?)?;
+            Some((connector.to_string(), serial))
+        })
+        .collect()
+}
+
+// The kernel calls HDMI-A-1 what the modesetting driver calls HDMI-1 and the intel one HDMI1.
+fn connector_name(name: &str) -> String {
+    name.replace("-A-", "-").replace('-', "").to_lowercase()
+}
+
+// The serial string descriptor (tag 0xff) when there is one, else the 32 bit serial number.
+pub fn parse_edid_serial(edid: &[u8]) -> Option<String> {
+    if edid.len() < 128 || edid[0..8] != [0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0] {
+        return None;
+    }
+
+    for offset in [54, 72, 90, 108].iter() {
+        let descriptor = &edid[*offset..*offset + 18];
+        if descriptor[0..3] == [0, 0, 0] && descriptor[3] == 0xff {
+            let text = String::from_utf8_lossy(&descriptor[5..]);
+            let serial = text.split('\n').next().unwrap_or("").trim();
+            if !serial.is_empty() {
+                return Some(serial.to_string());
+            }
+        }
+    }
+
+    match u32::from_le_bytes([edid[12], edid[13], edid[14], edid[15]]) {
+        0 => None,
+        n => Some(n.to_string()),
+    }
+}
+
+// Wayland transforms are clockwise degrees, optionally prefixed with "flipped-".
+fn transform(t: &str) -> Rotation {
+    Rotation::from_name(t.trim_start_matches("flipped").trim_start_matches('-')).unwrap_or(Rotation::Normal)
+}
+
+// Wayland reports the mode as the panel sees it, not as it is shown.
+fn rotated(mut m: Monitor) -> Monitor {
+    if m.rotation == Rotation::Left || m.rotation == Rotation::Right {
+        std::mem::swap(&mut m.width, &mut m.height);
+        std::mem::swap(&mut m.width_mm, &mut m.height_mm);
+    }
+
+    m
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::runner::FakeRunner;
+
+    // eDP-1 is primary, HDMI-1 is rotated left to the left of it and DP-1 carries a monitor
+    // made with `xrandr --setmonitor SIDE auto DP-1`.
+    pub(crate) const LIST_ACTIVE_MONITORS: &str = "\
+Monitors: 3
+ 0: +*eDP-1 1920/344x1080/193+0+0  eDP-1
+ 1: +HDMI-1 1080/336x1920/597-1080-420  HDMI-1
+ 2: SIDE 2560/597x1440/336+1920+0  DP-1
+";
+
+    const QUERY: &str = "\
+Screen 0: minimum 320 x 200, current 4480 x 1920, maximum 16384 x 16384
+eDP-1 connected primary 1920x1080+0+0 (normal left inverted right x axis y axis) 344mm x 193mm
+   1920x1080     60.01*+  59.97    59.96    59.93
+   1680x1050     59.95    59.88
+HDMI-1 connected 1080x1920-1080-420 left (normal left inverted right x axis y axis) 597mm x 336mm
+   1920x1080     60.00*+  50.00    59.94
+   1280x720      60.00    50.00    59.94
+DP-1 connected 2560x1440+1920+0 (normal left inverted right x axis y axis) 597mm x 336mm
+   2560x1440     59.95*+
+DP-2 disconnected (normal left inverted right x axis y axis)
+HDMI-2 connected (normal left inverted right x axis y axis)
+   1920x1080     60.00 +
+";
+
+    pub(crate) const SWAY_OUTPUTS: &str = r#"[
+  {
+    "id": 3,
+    "type": "output",
+    "name": "eDP-1",
+    "rect": {"x": 0, "y": 0, "width": 1920, "height": 1080},
+    "primary": false,
+    "make": "BOE",
+    "model": "0x0747",
+    "serial": "Unknown",
+    "active": true,
+    "dpms": true,
+    "power": true,
+    "scale": 1.0,
+    "subpixel_hinting": "rgb",
+    "transform": "normal",
+    "current_workspace": "1",
+    "modes": [{"width": 1920, "height": 1080, "refresh": 60052}],
+    "current_mode": {"width": 1920, "height": 1080, "refresh": 60052}
+  },
+  {
+    "id": 4,
+    "type": "output",
+    "name": "DP-2",
+    "rect": {"x": 1920, "y": -420, "width": 1440, "height": 2560},
+    "primary": false,
+    "make": "Dell Inc.",
+    "model": "DELL U2715H",
+    "serial": "GH85D6CS1AHL",
+    "active": true,
+    "dpms": true,
+    "power": true,
+    "scale": 1.0,
+    "subpixel_hinting": "unknown",
+    "transform": "90",
+    "current_workspace": "2",
+    "modes": [{"width": 2560, "height": 1440, "refresh": 59951}],
+    "current_mode": {"width": 2560, "height": 1440, "refresh": 59951}
+  },
+  {
+    "id": 5,
+    "type": "output",
+    "name": "HDMI-A-1",
+    "rect": {"x": 0, "y": 0, "width": 0, "height": 0},
+    "primary": false,
+    "make": "Goldstar Company Ltd",
+    "model": "LG TV",
+    "serial": "0x01010101",
+    "active": false,
+    "dpms": false,
+    "power": false,
+    "current_workspace": null,
+    "modes": [{"width": 3840, "height": 2160, "refresh": 60000}],
+    "current_mode": {"width": 0, "height": 0, "refresh": 0}
+  }
+]"#;
+
+    const WLR_RANDR: &str = r#"[
+  {
+    "name": "DP-1",
+    "description": "Dell Inc. DELL U2415 7MT0167B2YNL (DP-1)",
+    "make": "Dell Inc.",
+    "model": "DELL U2415",
+    "serial": "7MT0167B2YNL",
+    "physical_size": {"width": 520, "height": 320},
+    "enabled": true,
+    "modes": [
+      {"width": 1920, "height": 1200, "refresh": 59.950001, "preferred": true, "current": true},
+      {"width": 1920, "height": 1080, "refresh": 60.000000, "preferred": false, "current": false}
+    ],
+    "position": {"x": 0, "y": 0},
+    "transform": "90",
+    "scale": 1.000000,
+    "adaptive_sync": false
+  },
+  {
+    "name": "HDMI-A-1",
+    "description": "Unknown",
+    "make": "Unknown",
+    "model": "Unknown",
+    "serial": "Unknown",
+    "physical_size": {"width": 0, "height": 0},
+    "enabled": false,
+    "modes": [
+      {"width": 1920, "height": 1080, "refresh": 60.000000, "preferred": true, "current": false}
+    ],
+    "position": {"x": 0, "y": 0},
+    "transform": "normal",
+    "scale": 1.000000,
+    "adaptive_sync": false
+  }
+]"#;
+
+    // /sys/class/drm/card0-DP-1/edid of a Dell U2415.
+    const EDID: [&str; 8] = [
+        "00ffffffffffff0010aca0a0534c304c",
+        "1b1a0104a53420783aee95a3544c9926",
+        "0f5054a54b00714f8180a940d1c0d100",
+        "010101010101283c80a070b023403020",
+        "360006442100001a000000ff00374d54",
+        "303136374232594e4c0a000000fc0044",
+        "454c4c2055323431350a2020000000fd",
+        "00384c1e5111000a2020202020200035",
+    ];
+
+    fn edid() -> Vec<u8> {
+        let hex = EDID.concat();
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn active_monitors() {
+        let monitors = parse_active_monitors(LIST_ACTIVE_MONITORS);
+
+        assert_eq!(monitors.len(), 3);
+        let mut edp = Monitor::new("eDP-1", 1920, 1080, 0, 0);
+        edp.primary = true;
+        edp.width_mm = 344;
+        edp.height_mm = 193;
+        assert_eq!(monitors[0], edp);
+
+        assert_eq!((monitors[1].x, monitors[1].y), (-1080, -420));
+        assert_eq!(monitors[1].resolution(), "1080x1920");
+        assert!(!monitors[1].primary);
+
+        // Named after the output, not the monitor.
+        assert_eq!(monitors[2].name, "DP-1");
+        assert_eq!((monitors[2].resolution(), monitors[2].x), ("2560x1440".to_string(), 1920));
+    }
+
+    #[test]
+    fn query_rotations() {
+        assert_eq!(
+            parse_query_rotations(QUERY),
+            vec!(
+                ("eDP-1".to_string(), Rotation::Normal),
+                ("HDMI-1".to_string(), Rotation::Left),
+                ("DP-1".to_string(), Rotation::Normal),
+            )
+        );
+    }
+
+    #[test]
+    fn sway_outputs() {
+        let monitors = parse_sway_outputs(SWAY_OUTPUTS).unwrap();
+
+        assert_eq!(monitors.len(), 2);
+        assert_eq!((monitors[0].name.as_str(), monitors[0].serial.as_str()), ("eDP-1", ""));
+        let dp = &monitors[1];
+        assert_eq!(dp.rotation, Rotation::Right);
+        assert_eq!(dp.resolution(), "1440x2560");
+        assert_eq!((dp.x, dp.y), (1920, -420));
+        assert_eq!(dp.serial, "GH85D6CS1AHL");
+    }
+
+    #[test]
+    fn wlr_randr() {
+        let monitors = parse_wlr_randr(WLR_RANDR).unwrap();
+
+        assert_eq!(monitors.len(), 1);
+        let mut dp = Monitor::new("DP-1", 1200, 1920, 0, 0);
+        dp.rotation = Rotation::Right;
+        dp.width_mm = 320;
+        dp.height_mm = 520;
+        dp.serial = "7MT0167B2YNL".to_string();
+        assert_eq!(monitors[0], dp);
+    }
+
+    #[test]
+    fn wayland_without_wlroots_falls_back_to_xrandr() {
+        let runner = FakeRunner::new();
+        runner.fail("swaymsg", Some(1), "swaymsg: command not found");
+        runner.fail("wlr-randr", Some(1), "wlr-randr: command not found");
+        runner.reply("xrandr", LIST_ACTIVE_MONITORS);
+
+        let monitors = get_wayland_monitors(&runner).unwrap();
+        assert_eq!(monitors.len(), 3);
+        // Every program runs once.
+        assert_eq!(
+            runner.calls(),
+            vec!(
+                vec!("swaymsg", "-t", "get_outputs", "-r"),
+                vec!("wlr-randr", "--json"),
+                vec!("xrandr", "--listactivemonitors"),
+            )
+        );
+
+        // A compositor that lists nothing is no better.
+        runner.reply("wlr-randr", "[]");
+        assert_eq!(get_wayland_monitors(&runner).unwrap().len(), 3);
+
+        runner.reply("swaymsg", SWAY_OUTPUTS);
+        assert_eq!(get_wayland_monitors(&runner).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn edid_serial() {
+        let mut edid = edid();
+        assert_eq!(parse_edid_serial(&edid).as_deref(), Some("7MT0167B2YNL"));
+
+        // Without the descriptor the serial number is used.
+        edid[75] = 0xfe;
+        assert_eq!(parse_edid_serial(&edid).as_deref(), Some("1278233683"));
+
+        assert_eq!(parse_edid_serial(&edid[..100]), None);
+        edid[0] = 1;
+        assert_eq!(parse_edid_serial(&edid), None);
+    }
+
+    #[test]
+    fn connector_names() {
+        assert_eq!(connector_name("HDMI-A-1"), connector_name("HDMI-1"));
+        assert_eq!(connector_name("DP-1"), connector_name("DP1"));
+        assert_ne!(connector_name("DP-1"), connector_name("eDP-1"));
+    }
+}
@@ -0,0 +1,247 @@
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::fs::read_dir;
+use std::time::SystemTime;
+
+pub const ORDERS: [&str; 5] = ["random", "shuffle", "name", "mtime", "reverse-mtime"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    // Independent pick on every change, repeats included.
+    Random,
+    // Every file once in a random order, then reshuffle.
+    Shuffle,
+    Name,
+    // Oldest first.
+    Mtime,
+    // Newest first.
+    ReverseMtime,
+}
+
+impl Order {
+    pub fn from_name(name: &str) -> Option<Order> {
+        match name {
+            "random" => Some(Order::Random),
+            "shuffle" => Some(Order::Shuffle),
+            "name" => Some(Order::Name),
+            "mtime" => Some(Order::Mtime),
+            "reverse-mtime" => Some(Order::ReverseMtime),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Order::Random => "random",
+            Order::Shuffle => "shuffle",
+            Order::Name => "name",
+            Order::Mtime => "mtime",
+            Order::ReverseMtime => "reverse-mtime",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    remaining: Vec<String>,
+    shown: HashSet<String>,
+    last: Option<String>,
+}
+
+// Walks every directory without repeating a file until all of them were shown.
+// The directory is read on every pick, so added files join the current round and removed ones leave it.
+pub struct Playlist {
+    order: Order,
+    queues: HashMap<String, Queue>,
+}
+
+impl Playlist {
+    pub fn new(order: Order) -> Playlist {
+        Playlist {
+            order,
+            queues: HashMap::new(),
+        }
+    }
+
+    pub fn next(&mut self, dir: &str) -> String {
+        let files = list_files(dir);
+        if files.is_empty() {
+            return dir.to_string();
+        }
+        // Picked from the files only, the directory may hold the ones of other monitors.
+        if self.order == Order::Random {
+            return files[rand::thread_rng().gen_range(0, files.len())].clone();
+        }
+
+        let order = self.order;
+        let queue = self.queues.entry(dir.to_string()).or_default();
+        queue.remaining.retain(|f| files.contains(f));
+        queue.shown.retain(|f| files.contains(f));
+        for f in files.iter() {
+            if !queue.remaining.contains(f) && !queue.shown.contains(f) {
+                let index = match order {
+                    Order::Shuffle => rand::thread_rng().gen_range(0, queue.remaining.len() + 1),
+                    _ => queue.remaining.len(),
+                };
+                queue.remaining.insert(index, f.to_string());
+            }
+        }
+
+        if queue.remaining.is_empty() {
+            queue.shown.clear();
+            queue.remaining = files;
+            if order == Order::Shuffle {
+                rand::thread_rng().shuffle(&mut queue.remaining);
+                // Don't show the last picture of a round again as the first of the next one.
+                if queue.remaining.len() > 1 && queue.remaining.first() == queue.last.as_ref() {
+                    let last = queue.remaining.len() - 1;
+                    queue.remaining.swap(0, last);
+                }
+            }
+        }
+        sort(&mut queue.remaining, order);
+
+        let file = queue.remaining.remove(0);
+        queue.shown.insert(file.clone());
+        queue.last = Some(file.clone());
+
+        file
+    }
+}
+
+fn list_files(dir: &str) -> Vec<String> {
+    match read_dir(dir) {
+        Ok(r) => r
+            .flatten()
+            .filter(|e| e.path().is_file())
+            .filter_map(|e| e.file_name().into_string().ok())
+            .map(|n| format!("{}/{}", dir.trim_end_matches('/'), n))
+            .collect(),
+        Err(_e) => vec!(),
+    }
+}
+
+fn sort(files: &mut [String], order: Order) {
+    let mtime = |f: &String| {
+        std::fs::metadata(f)
+            .and_then(|m| m.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+    };
+
+    match order {
+        Order::Name => files.sort(),
+        Order::Mtime => files.sort_by_key(mtime),
+        Order::ReverseMtime => files.sort_by_key(|f| std::cmp::Reverse(mtime(f))),
+        Order::Random | Order::Shuffle => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::gen_rand_string;
+    use std::fs::{create_dir_all, remove_dir_all, remove_file, write, File};
+    use std::time::Duration;
+
+    // A directory holding `files`, empty ones.
+    fn dir_with(files: &[&str]) -> String {
+        let dir = std::env::temp_dir().join(format!("wallpaper-test-{}", gen_rand_string()));
+        create_dir_all(&dir).unwrap();
+        for f in files.iter() {
+            write(dir.join(f), "").unwrap();
+        }
+
+        dir.display().to_string()
+    }
+
+    // The names of the next `count` picks.
+    fn picks(playlist: &mut Playlist, dir: &str, count: usize) -> Vec<String> {
+        (0..count)
+            .map(|_| playlist.next(dir).trim_start_matches(&format!("{}/", dir)).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn shuffle_shows_every_file_once_per_bag() {
+        let files = ["a.jpg", "b.jpg", "c.jpg", "d.jpg", "e.jpg"];
+        let dir = dir_with(&files);
+        let all = files.iter().map(|f| f.to_string()).collect::<HashSet<String>>();
+
+        let mut playlist = Playlist::new(Order::Shuffle);
+        let mut last = None;
+        for _ in 0..10 {
+            let bag = picks(&mut playlist, &dir, files.len());
+            assert_eq!(bag.iter().cloned().collect::<HashSet<String>>(), all, "{:?}", bag);
+            // Reshuffled once empty, without the last file of a bag opening the next one.
+            assert_ne!(Some(&bag[0]), last.as_ref());
+            last = bag.last().cloned();
+        }
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn shuffle_takes_in_added_files_and_drops_removed_ones() {
+        let dir = dir_with(&["a.jpg", "b.jpg", "c.jpg"]);
+        let mut playlist = Playlist::new(Order::Shuffle);
+        let shown = picks(&mut playlist, &dir, 1);
+
+        let removed = ["a.jpg", "b.jpg", "c.jpg"].iter().find(|f| **f != shown[0]).unwrap().to_string();
+        remove_file(format!("{}/{}", dir, removed)).unwrap();
+        write(format!("{}/d.jpg", dir), "").unwrap();
+
+        let mut rest = picks(&mut playlist, &dir, 2);
+        rest.sort();
+        let mut expected = ["a.jpg", "b.jpg", "c.jpg", "d.jpg"]
+            .iter()
+            .map(|f| f.to_string())
+            .filter(|f| *f != shown[0] && *f != removed)
+            .collect::<Vec<String>>();
+        expected.sort();
+        assert_eq!(rest, expected);
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn name_order_wraps_around() {
+        let dir = dir_with(&["c.jpg", "a.jpg", "b.jpg"]);
+        let mut playlist = Playlist::new(Order::Name);
+        assert_eq!(picks(&mut playlist, &dir, 4), vec!("a.jpg", "b.jpg", "c.jpg", "a.jpg"));
+
+        // Mid round, a new file joins in its place and a removed one is skipped.
+        remove_file(format!("{}/b.jpg", dir)).unwrap();
+        write(format!("{}/bb.jpg", dir), "").unwrap();
+        assert_eq!(picks(&mut playlist, &dir, 3), vec!("bb.jpg", "c.jpg", "a.jpg"));
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn mtime_orders() {
+        let dir = dir_with(&["old.jpg", "new.jpg", "mid.jpg"]);
+        let now = SystemTime::now();
+        for (f, age) in [("old.jpg", 300), ("mid.jpg", 200), ("new.jpg", 100)].iter() {
+            let file = File::options().write(true).open(format!("{}/{}", dir, f)).unwrap();
+            file.set_modified(now - Duration::from_secs(*age)).unwrap();
+        }
+
+        let mut playlist = Playlist::new(Order::Mtime);
+        assert_eq!(picks(&mut playlist, &dir, 3), vec!("old.jpg", "mid.jpg", "new.jpg"));
+        let mut playlist = Playlist::new(Order::ReverseMtime);
+        assert_eq!(picks(&mut playlist, &dir, 3), vec!("new.jpg", "mid.jpg", "old.jpg"));
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn random_skips_directories() {
+        let dir = std::env::temp_dir().join(format!("wallpaper-test-{}", gen_rand_string()));
+        create_dir_all(dir.join("1920x1080")).unwrap();
+        create_dir_all(dir.join("HDMI-1")).unwrap();
+        write(dir.join("a.jpg"), "").unwrap();
+        let dir = dir.display().to_string();
+
+        let mut playlist = Playlist::new(Order::Random);
+        for _ in 0..20 {
+            assert_eq!(playlist.next(&dir), format!("{}/a.jpg", dir));
+        }
+        let _ = remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,267 @@
+use crate::cancel::Cancel;
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// How often a cancellable program is checked on.
+const CANCEL_POLL: Duration = Duration::from_millis(50);
+
+// Every program we run goes through here with its arguments as they are, no shell in between,
+// so paths with spaces, quotes or '$' reach the program untouched.
+pub trait CommandRunner: Send + Sync {
+    // Waits for the program and returns what it printed. A non-zero status is an Error::Command
+    // with its stderr.
+    fn run(&self, program: &str, args: &[&str]) -> Result<String>;
+
+    // Like run, but the program is killed once `cancel` is set, for the long ones like ffmpeg.
+    fn run_cancellable(&self, program: &str, args: &[&str], _cancel: &Cancel) -> Result<String> {
+        self.run(program, args)
+    }
+
+    // Starts a program that keeps running until stopped, like swaybg.
+    fn spawn(&self, program: &str, args: &[&str]) -> Result<Box<dyn Process>>;
+
+    // Stops a program spawned by an earlier run of ours, if `pid` still is `program`.
+    fn kill(&self, program: &str, pid: u32) -> Result<()>;
+}
+
+// A program started with CommandRunner::spawn.
+pub trait Process: Send {
+    fn pid(&self) -> u32;
+
+    fn stop(&mut self);
+}
+
+// Runs the real programs.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[&str]) -> Result<String> {
+        let output = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| not_started(program, e))?;
+
+        finished(program, output.status, &output.stdout, &output.stderr)
+    }
+
+    fn run_cancellable(&self, program: &str, args: &[&str], cancel: &Cancel) -> Result<String> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| not_started(program, e))?;
+        // Read while it runs, a full pipe would block it.
+        let stdout = drain(child.stdout.take());
+        let stderr = drain(child.stderr.take());
+
+        let status = loop {
+            if let Some(status) = child.try_wait().map_err(|e| Error::dependency(program, e))? {
+                break status;
+            }
+            if cancel.is_cancelled() {
+                // Comes back as killed on the next round.
+                let _ = child.kill();
+            }
+            thread::sleep(CANCEL_POLL);
+        };
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+
+        finished(program, status, &stdout, &stderr)
+    }
+
+    fn spawn(&self, program: &str, args: &[&str]) -> Result<Box<dyn Process>> {
+        let child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| not_started(program, e))?;
+
+        Ok(Box::new(child))
+    }
+
+    fn kill(&self, program: &str, pid: u32) -> Result<()> {
+        // The pid may have gone to another program since.
+        match std::fs::read_to_string(format!("/proc/{}/comm", pid)) {
+            Ok(comm) if comm.trim() == program => {}
+            _ => return Ok(()),
+        }
+
+        nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid as i32), nix::sys::signal::SIGTERM)
+            .map_err(|e| Error::dependency(program, format!("pid {}:{}", pid, e)))
+    }
+}
+
+impl Process for Child {
+    fn pid(&self) -> u32 {
+        self.id()
+    }
+
+    fn stop(&mut self) {
+        let _ = self.kill();
+        let _ = self.wait();
+    }
+}
+
+// A non-zero status is an Error::Command with what the program said on stderr.
+fn finished(program: &str, status: ExitStatus, stdout: &[u8], stderr: &[u8]) -> Result<String> {
+    if !status.success() {
+        Err(Error::Command {
+            program: program.to_string(),
+            status: status.code(),
+            stderr: String::from_utf8_lossy(stderr).trim().to_string(),
+            monitor: None,
+        })?
+    }
+
+    Ok(String::from_utf8_lossy(stdout).to_string())
+}
+
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut read = vec!();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut read);
+        }
+        read
+    })
+}
+
+fn not_started(program: &str, e: std::io::Error) -> Error {
+    match e.kind() {
+        ErrorKind::NotFound => Error::dependency(program, "does not exist, please install first"),
+        _ => Error::dependency(program, e),
+    }
+}
+
+// Records every call instead of running anything, for testing a backend without its programs.
+// Programs print nothing and succeed unless told otherwise with reply or fail.
+#[derive(Debug, Default, Clone)]
+pub struct FakeRunner {
+    calls: Arc<Mutex<Vec<Vec<String>>>>,
+    replies: Arc<Mutex<HashMap<String, Reply>>>,
+    // Spawned programs get pids from 100 on.
+    pids: Arc<AtomicU32>,
+}
+
+#[derive(Debug, Clone)]
+enum Reply {
+    Output(String),
+    Failure(Option<i32>, String),
+}
+
+impl FakeRunner {
+    pub fn new() -> FakeRunner {
+        FakeRunner::default()
+    }
+
+    // What `program` prints from now on.
+    pub fn reply(&self, program: &str, stdout: &str) {
+        self.set(program, Reply::Output(stdout.to_string()));
+    }
+
+    // `program` exits with `status` from now on, None for killed by a signal.
+    pub fn fail(&self, program: &str, status: Option<i32>, stderr: &str) {
+        self.set(program, Reply::Failure(status, stderr.to_string()));
+    }
+
+    // Every run and spawn so far, each as the program followed by its arguments, and every
+    // kill as `kill <pid>`.
+    pub fn calls(&self) -> Vec<Vec<String>> {
+        self.calls.lock().map(|c| c.clone()).unwrap_or_default()
+    }
+
+    fn set(&self, program: &str, reply: Reply) {
+        if let Ok(mut replies) = self.replies.lock() {
+            replies.insert(program.to_string(), reply);
+        }
+    }
+
+    fn record(&self, program: &str, args: &[&str]) -> Result<String> {
+        if let Ok(mut calls) = self.calls.lock() {
+            calls.push(std::iter::once(program).chain(args.iter().copied()).map(|a| a.to_string()).collect());
+        }
+        let reply = self.replies.lock().ok().and_then(|r| r.get(program).cloned());
+
+        match reply {
+            Some(Reply::Output(stdout)) => Ok(stdout),
+            Some(Reply::Failure(status, stderr)) => Err(Error::Command {
+                program: program.to_string(),
+                status,
+                stderr,
+                monitor: None,
+            }),
+            None => Ok(String::new()),
+        }
+    }
+}
+
+impl CommandRunner for FakeRunner {
+    fn run(&self, program: &str, args: &[&str]) -> Result<String> {
+        self.record(program, args)
+    }
+
+    fn spawn(&self, program: &str, args: &[&str]) -> Result<Box<dyn Process>> {
+        self.record(program, args)?;
+
+        Ok(Box::new(Stopped(100 + self.pids.fetch_add(1, Ordering::SeqCst))))
+    }
+
+    fn kill(&self, _program: &str, pid: u32) -> Result<()> {
+        self.record("kill", &[&pid.to_string()])?;
+
+        Ok(())
+    }
+}
+
+struct Stopped(u32);
+
+impl Process for Stopped {
+    fn pid(&self) -> u32 {
+        self.0
+    }
+
+    fn stop(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn a_cancelled_program_is_killed() {
+        let cancel = Cancel::new();
+        let canceller = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            canceller.cancel();
+        });
+
+        let started = Instant::now();
+        let e = SystemRunner.run_cancellable("sleep", &["30"], &cancel).unwrap_err();
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(matches!(e, Error::Command { status: None, .. }), "{:?}", e);
+    }
+
+    #[test]
+    fn a_cancellable_program_answers_like_run() {
+        let cancel = Cancel::new();
+        assert_eq!(SystemRunner.run_cancellable("echo", &["a b"], &cancel).unwrap(), "a b\n");
+        let e = SystemRunner.run_cancellable("sh", &["-c", "echo oops >&2; exit 3"], &cancel).unwrap_err();
+        assert_eq!(e.to_string(), "sh exited with 3: oops");
+    }
+}
@@ -0,0 +1,207 @@
+use crate::error::{Error, Result};
+use crate::function::get_runtime_file;
+use crate::log::Log;
+use std::fs::remove_file;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Sender};
+use std::thread::spawn;
+use std::time::Duration;
+
+// How long a client waits for the rotation to pick up its command.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+// How long we wait for a client to send its command.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Next,
+    Prev,
+    History,
+    Pause,
+    Resume,
+    // Pauses a running rotation, resumes a paused one.
+    Toggle,
+    // A picture shown on every monitor, or a directory to rotate from.
+    Set(String),
+    Status,
+    // Reads the wallpaper directory again.
+    Reload,
+    // Detects the monitors again, X11 sends it when RandR reports a new layout.
+    Refresh,
+    Quit,
+    // Seconds between two wallpapers.
+    Interval(i64),
+    // How fast videos and gifs play.
+    Speed(f64),
+    // Loops of a video or gif before it stops, 0 for forever.
+    LoopCount(u32),
+    // Plays a gif at the given fps, 0 for its own delays, or a video, instead of rotating.
+    Gif(u16, String),
+    Video(String),
+}
+
+impl Command {
+    pub fn from_line(line: &str) -> Option<Command> {
+        let line = line.trim();
+        let (name, arg) = line.split_once(' ').unwrap_or((line, ""));
+        match (name, arg.trim()) {
+            ("next", "") => Some(Command::Next),
+            ("prev", "") => Some(Command::Prev),
+            ("history", "") => Some(Command::History),
+            ("pause", "") => Some(Command::Pause),
+            ("resume", "") => Some(Command::Resume),
+            ("toggle", "") => Some(Command::Toggle),
+            ("set", path) if !path.is_empty() => Some(Command::Set(path.to_string())),
+            ("status", "") => Some(Command::Status),
+            ("reload", "") => Some(Command::Reload),
+            ("refresh", "") => Some(Command::Refresh),
+            ("quit", "") => Some(Command::Quit),
+            ("interval", secs) => secs.parse().ok().filter(|s| *s > 0).map(Command::Interval),
+            ("speed", speed) => speed.parse().ok().filter(|s: &f64| s.is_finite() && *s > 0.0).map(Command::Speed),
+            ("loop-count", count) => count.parse().ok().map(Command::LoopCount),
+            // The path goes last, it may hold spaces.
+            ("gif", arg) => {
+                let (fps, path) = arg.split_once(' ')?;
+                let fps = fps.parse().ok()?;
+                Some(Command::Gif(fps, path.trim().to_string()))
+            }
+            ("video", path) if !path.is_empty() => Some(Command::Video(path.to_string())),
+            _ => None,
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        match self {
+            Command::Next => "next".to_string(),
+            Command::Prev => "prev".to_string(),
+            Command::History => "history".to_string(),
+            Command::Pause => "pause".to_string(),
+            Command::Resume => "resume".to_string(),
+            Command::Toggle => "toggle".to_string(),
+            Command::Set(path) => format!("set {}", path),
+            Command::Status => "status".to_string(),
+            Command::Reload => "reload".to_string(),
+            Command::Refresh => "refresh".to_string(),
+            Command::Quit => "quit".to_string(),
+            Command::Interval(secs) => format!("interval {}", secs),
+            Command::Speed(speed) => format!("speed {}", speed),
+            Command::LoopCount(count) => format!("loop-count {}", count),
+            Command::Gif(fps, path) => format!("gif {} {}", fps, path),
+            Command::Video(path) => format!("video {}", path),
+        }
+    }
+}
+
+// A command from a client, the rotation answers through `reply`.
+pub struct Request {
+    pub command: Command,
+    pub reply: Sender<String>,
+}
+
+pub fn socket_path() -> Result<PathBuf> {
+    get_runtime_file("sock")
+}
+
+// One line per connection: the client writes a command, we write the answer and close.
+// Answers are "ok", "error: <why>" or the text asked for (status, history).
+// Every connection is served on its own thread, so a client that never sends its line only
+// holds up itself. Clients that go away mid-exchange are told to `log`. A quit goes to the
+// rotation like any other command, ending the process is left to the caller.
+pub fn listen(requests: Sender<Request>, log: Log) -> Result<()> {
+    let path = socket_path()?;
+    let _ = remove_file(&path);
+    let listener = UnixListener::bind(&path).map_err(|e| Error::io(path.display(), e))?;
+
+    spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let (requests, log) = (requests.clone(), log.clone());
+            spawn(move || {
+                if let Err(e) = serve(stream, &requests) {
+                    log.message(format!("Control socket error:{}", e));
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+fn serve(stream: UnixStream, requests: &Sender<Request>) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+
+    let answer = match Command::from_line(&line) {
+        Some(command) => {
+            let (reply, answer) = channel();
+            match requests.send(Request { command, reply }) {
+                Ok(()) => answer
+                    .recv_timeout(REPLY_TIMEOUT)
+                    .unwrap_or_else(|_e| "error: the rotation did not answer".to_string()),
+                Err(_e) => "error: the rotation is not running".to_string(),
+            }
+        }
+        None => format!("error: unknown command {}", line.trim()),
+    };
+
+    (&stream).write_all(answer.as_bytes())?;
+    if !answer.ends_with('\n') {
+        (&stream).write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+// The running instance refusing a command is an Instance error too.
+pub fn send(command: &Command) -> Result<String> {
+    let path = socket_path()?;
+    let mut stream = UnixStream::connect(&path)
+        .map_err(|e| Error::Instance(format!("No running wallpaper found at {}:{}", path.display(), e)))?;
+    let exchange = |stream: &mut UnixStream| -> std::io::Result<String> {
+        stream.write_all(format!("{}\n", command.to_line()).as_bytes())?;
+        stream.shutdown(std::net::Shutdown::Write)?;
+
+        let mut answer = String::new();
+        stream.read_to_string(&mut answer)?;
+        Ok(answer)
+    };
+    let answer = exchange(&mut stream).map_err(|e| Error::io(path.display(), e))?;
+    if let Some(e) = answer.strip_prefix("error: ") {
+        Err(Error::Instance(e.trim().to_string()))?
+    }
+
+    Ok(answer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_survive_a_round_trip() {
+        let commands = vec!(
+            Command::Next,
+            Command::Toggle,
+            Command::Refresh,
+            Command::Set("/home/me/My Pictures/a b.jpg".to_string()),
+            Command::Interval(90),
+            Command::Speed(1.5),
+            Command::LoopCount(0),
+            Command::Gif(12, "/home/me/my  gifs/a cat.gif".to_string()),
+            Command::Video("/home/me/Videos/the sea.mp4".to_string()),
+        );
+
+        for command in commands {
+            assert_eq!(Command::from_line(&format!("{}\n", command.to_line())), Some(command.clone()), "{:?}", command);
+        }
+    }
+
+    #[test]
+    fn bad_lines_are_no_commands() {
+        for line in ["", "jump", "next now", "set", "interval 0", "interval -5", "speed nan", "gif 12", "gif x a.gif"] {
+            assert_eq!(Command::from_line(line), None, "{}", line);
+        }
+    }
+}
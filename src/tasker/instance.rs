@@ -0,0 +1,77 @@
+use crate::error::{Error, Result};
+use crate::function::get_runtime_file;
+use crate::tasker::control::{self, Command};
+use nix::fcntl::{flock, FlockArg, OFlag};
+use nix::sys::signal::{kill, SIGTERM};
+use nix::unistd::Pid;
+use std::fs::{read_to_string, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+// How long --replace waits for the old instance to go away.
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Holds the lock on the pidfile, the kernel drops it when the process dies.
+pub struct Instance {
+    _file: File,
+}
+
+pub fn pidfile_path() -> Result<PathBuf> {
+    get_runtime_file("pid")
+}
+
+// None when another instance holds the lock.
+pub fn lock() -> Result<Option<Instance>> {
+    let path = pidfile_path()?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        // The pid of the running instance stays readable until we hold the lock.
+        .truncate(false)
+        // Never truncate whatever a planted symlink points to.
+        .custom_flags(OFlag::O_NOFOLLOW.bits())
+        .open(&path)
+        .map_err(|e| Error::io(path.display(), e))?;
+
+    if flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock).is_err() {
+        return Ok(None);
+    }
+
+    file.set_len(0)
+        .and_then(|_| writeln!(file, "{}", std::process::id()))
+        .map_err(|e| Error::io(path.display(), e))?;
+
+    Ok(Some(Instance { _file: file }))
+}
+
+pub fn running_pid() -> Option<i32> {
+    read_to_string(pidfile_path().ok()?).ok()?.trim().parse().ok()
+}
+
+// Asks the running instance to quit, through its socket or with a SIGTERM, and takes its place.
+pub fn replace() -> Result<Instance> {
+    if control::send(&Command::Quit).is_err() {
+        if let Some(pid) = running_pid() {
+            kill(Pid::from_raw(pid), SIGTERM)
+                .map_err(|e| Error::Instance(format!("Couldn't stop the running wallpaper (pid {}):{}", pid, e)))?;
+        }
+    }
+
+    let deadline = Instant::now() + REPLACE_TIMEOUT;
+    loop {
+        if let Some(instance) = lock()? {
+            return Ok(instance);
+        }
+        if Instant::now() >= deadline {
+            Err(Error::Instance(format!(
+                "The running wallpaper (pid {}) did not quit",
+                running_pid().map(|p| p.to_string()).unwrap_or_else(|| "?".to_string())
+            )))?
+        }
+        sleep(Duration::from_millis(100));
+    }
+}
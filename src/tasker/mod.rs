@@ -1 +1,3 @@
+pub mod control;
+pub mod instance;
 pub mod shutdown;
\ No newline at end of file
@@ -1,8 +1,14 @@
 #[cfg(unix)]
 mod platform {
-    use nix::sys::signal::{SigSet, SIGINT, SIGQUIT, SIGTERM};
+    use crate::tasker::control::{Command, Request};
+    use nix::sys::signal::{SigSet, Signal, SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGUSR1, SIGUSR2};
+    use std::sync::mpsc::{channel, Sender};
 
-    pub struct ShutdownSignal(SigSet);
+    // Blocks the signals in every thread spawned afterwards, they are only taken by at_exit.
+    pub struct ShutdownSignal {
+        mask: SigSet,
+        requests: Option<Sender<Request>>,
+    }
 
     impl ShutdownSignal {
         pub fn new() -> ShutdownSignal {
@@ -11,14 +17,58 @@ mod platform {
             mask.add(SIGINT);
             mask.add(SIGQUIT);
             mask.add(SIGTERM);
+            mask.add(SIGUSR1);
+            mask.add(SIGUSR2);
+            mask.add(SIGHUP);
             mask.thread_block().unwrap();
 
-            ShutdownSignal(mask)            
+            ShutdownSignal {
+                mask,
+                requests: None,
+            }
+        }
+
+        // Where SIGUSR1, SIGUSR2 and SIGHUP go, they are ignored without it.
+        pub fn route(&mut self, requests: Sender<Request>) {
+            self.requests = Some(requests);
         }
 
         pub fn at_exit<F: FnOnce(usize)>(&self, handle: F) {
-            let signal = self.0.wait().unwrap();
-            handle(signal as usize);
+            loop {
+                let signal = self.mask.wait().unwrap();
+                match command(signal) {
+                    Some(command) => self.dispatch(command),
+                    None => {
+                        // The rotation stops on its own, handle waits for it.
+                        self.dispatch(Command::Quit);
+                        handle(signal as usize);
+                        return;
+                    }
+                }
+            }
+        }
+
+        fn dispatch(&self, command: Command) {
+            if let Some(requests) = &self.requests {
+                // Nobody reads the answer of a signal.
+                let (reply, _answer) = channel();
+                let _ = requests.send(Request { command, reply });
+            }
+        }
+    }
+
+    impl Default for ShutdownSignal {
+        fn default() -> ShutdownSignal {
+            ShutdownSignal::new()
+        }
+    }
+
+    fn command(signal: Signal) -> Option<Command> {
+        match signal {
+            SIGUSR1 => Some(Command::Next),
+            SIGUSR2 => Some(Command::Toggle),
+            SIGHUP => Some(Command::Reload),
+            _ => None,
         }
     }
 }
@@ -0,0 +1,379 @@
+use crate::compose::{image_error, span};
+use crate::error::{Error, Result};
+use crate::function::ScaleMode;
+use crate::monitor::Monitor;
+use x11rb::connection::{Connection, RequestConnection};
+use x11rb::protocol::randr::{ConnectionExt as _, NotifyMask};
+use x11rb::protocol::Event;
+use x11rb::protocol::xproto::{
+    AtomEnum, ChangeWindowAttributesAux, CloseDown, ConnectionExt as _, CreateGCAux, ImageFormat, ImageOrder,
+    PropMode, Screen,
+};
+use x11rb::rust_connection::RustConnection;
+use x11rb::wrapper::ConnectionExt as _;
+use image::imageops;
+use image::{Rgb, RgbImage};
+use std::path::Path;
+use std::thread::spawn;
+
+// Properties read by compositors and pseudo-transparent terminals to find the root background.
+const ROOT_ATOMS: [&[u8]; 2] = [b"_XROOTPMAP_ID", b"ESETROOT_PMAP_ID"];
+
+// Keeps one connection open for the whole rotation, so a new frame costs a decode and an upload, not a fork.
+pub struct RootWindow {
+    conn: RustConnection,
+    screen_num: usize,
+    pixmap: Option<u32>,
+}
+
+impl RootWindow {
+    pub fn connect() -> Result<RootWindow> {
+        let (conn, screen_num) = x11rb::connect(None)?;
+
+        Ok(RootWindow {
+            conn,
+            screen_num,
+            pixmap: None,
+        })
+    }
+
+    fn screen(&self) -> &Screen {
+        &self.conn.setup().roots[self.screen_num]
+    }
+
+    // Monitors in RandR order, which is the order `xrandr --listactivemonitors` prints them in.
+    pub fn monitors(&self) -> Result<Vec<Monitor>> {
+        let screen = self.screen();
+        let monitors = self.conn.randr_get_monitors(screen.root, true)?.reply()?.monitors;
+        if monitors.is_empty() {
+            let (width, height) = (screen.width_in_pixels as u32, screen.height_in_pixels as u32);
+            return Ok(vec!(Monitor::new("default", width, height, 0, 0)));
+        }
+
+        let mut ret = vec!();
+        for m in monitors.iter() {
+            let name = self.conn.get_atom_name(m.name)?.reply()?.name;
+            let mut monitor = Monitor::new(
+                &String::from_utf8_lossy(&name),
+                m.width as u32,
+                m.height as u32,
+                m.x as i32,
+                m.y as i32,
+            );
+            monitor.primary = m.primary;
+            monitor.width_mm = m.width_in_millimeters;
+            monitor.height_mm = m.height_in_millimeters;
+            ret.push(monitor);
+        }
+
+        Ok(ret)
+    }
+
+    pub fn set_wallpaper(&mut self, wallpaper_paths: &[String], mode: ScaleMode) -> Result<()> {
+        if wallpaper_paths.is_empty() {
+            return Ok(());
+        }
+
+        let monitors = self.monitors()?;
+        let screen = self.screen();
+        let (width, height) = (screen.width_in_pixels as u32, screen.height_in_pixels as u32);
+        let canvas = span(wallpaper_paths, &monitors, width, height, mode)?;
+
+        self.show(&canvas)
+    }
+
+    // Shows a picture of the whole screen from its top left corner, as `capture` saved it.
+    pub fn set_picture(&mut self, picture: &RgbImage) -> Result<()> {
+        let screen = self.screen();
+        let mut canvas = RgbImage::new(screen.width_in_pixels as u32, screen.height_in_pixels as u32);
+        imageops::replace(&mut canvas, picture, 0, 0);
+
+        self.show(&canvas)
+    }
+
+    // Saves what the root window shows to `path`, from the pixmap a setter named in
+    // _XROOTPMAP_ID. Returns false when there is none.
+    pub fn capture(&self, path: &Path) -> Result<bool> {
+        let root = self.screen().root;
+        let atom = self.conn.intern_atom(false, ROOT_ATOMS[0])?.reply()?.atom;
+        let pixmap = self
+            .conn
+            .get_property(false, root, atom, AtomEnum::PIXMAP, 0, 1)?
+            .reply()?
+            .value32()
+            .and_then(|mut v| v.next());
+        let pixmap = match pixmap {
+            Some(p) if p != 0 => p,
+            _ => return Ok(false),
+        };
+        let geometry = self.conn.get_geometry(pixmap)?.reply()?;
+        self.check_depth(geometry.depth)?;
+        let image = self
+            .conn
+            .get_image(ImageFormat::Z_PIXMAP, pixmap, 0, 0, geometry.width, geometry.height, !0)?
+            .reply()?;
+
+        let lsb_first = self.conn.setup().image_byte_order == ImageOrder::LSB_FIRST;
+        let mut picture = RgbImage::new(geometry.width as u32, geometry.height as u32);
+        for (pixel, bytes) in picture.pixels_mut().zip(image.data.chunks_exact(4)) {
+            *pixel = if lsb_first { Rgb([bytes[2], bytes[1], bytes[0]]) } else { Rgb([bytes[1], bytes[2], bytes[3]]) };
+        }
+        let name = path.display().to_string();
+        picture.save(path).map_err(|e| image_error(&name, e))?;
+
+        Ok(true)
+    }
+
+    // Pixels are uploaded and read as 32 bits.
+    fn check_depth(&self, depth: u8) -> Result<()> {
+        let bits_per_pixel = self
+            .conn
+            .setup()
+            .pixmap_formats
+            .iter()
+            .find(|f| f.depth == depth)
+            .map(|f| f.bits_per_pixel)
+            .unwrap_or(0);
+        if bits_per_pixel != 32 {
+            Err(Error::backend("X11", format!("Unsupported root window depth {} ({} bits per pixel)", depth, bits_per_pixel)))?
+        }
+
+        Ok(())
+    }
+
+    // Uploads a canvas of the screen size as the new root pixmap.
+    fn show(&mut self, canvas: &RgbImage) -> Result<()> {
+        let screen = self.screen();
+        let (root, depth) = (screen.root, screen.root_depth);
+        let (width, height) = (canvas.width() as u16, canvas.height() as u16);
+        self.check_depth(depth)?;
+        let lsb_first = self.conn.setup().image_byte_order == ImageOrder::LSB_FIRST;
+        // The pixmap has to outlive us, otherwise the background goes black as soon as we exit.
+        // Only a connection that sets one is kept, the server holds on to its slot until the
+        // next setter kills it.
+        self.conn.set_close_down_mode(CloseDown::RETAIN_PERMANENT)?;
+
+        let mut buffer = Vec::with_capacity(width as usize * height as usize * 4);
+        for pixel in canvas.pixels() {
+            let [r, g, b] = pixel.0;
+            buffer.extend_from_slice(&if lsb_first { [b, g, r, 0] } else { [0, r, g, b] });
+        }
+
+        let pixmap = self.conn.generate_id()?;
+        self.conn.create_pixmap(depth, pixmap, root, width, height)?;
+        let gc = self.conn.generate_id()?;
+        self.conn.create_gc(gc, pixmap, &CreateGCAux::new())?;
+
+        // Upload in as many rows as fit in one request.
+        let row = width as usize * 4;
+        let rows = ((self.conn.maximum_request_bytes() - 64) / row).clamp(1, height as usize);
+        for (i, chunk) in buffer.chunks(row * rows).enumerate() {
+            let y = (i * rows) as i16;
+            let lines = (chunk.len() / row) as u16;
+            self.conn
+                .put_image(ImageFormat::Z_PIXMAP, pixmap, gc, width, lines, 0, y, 0, depth, chunk)?;
+        }
+        self.conn.free_gc(gc)?;
+
+        self.replace_root_pixmap(root, pixmap)?;
+        self.conn.change_window_attributes(root, &ChangeWindowAttributesAux::new().background_pixmap(pixmap))?;
+        self.conn.clear_area(false, root, 0, 0, 0, 0)?;
+        self.conn.flush()?;
+
+        Ok(())
+    }
+
+    fn replace_root_pixmap(&mut self, root: u32, pixmap: u32) -> Result<()> {
+        let mut atoms = vec!();
+        for name in ROOT_ATOMS.iter() {
+            atoms.push(self.conn.intern_atom(false, name)?.reply()?.atom);
+        }
+
+        match self.pixmap.replace(pixmap) {
+            Some(old) => {
+                self.conn.free_pixmap(old)?;
+            }
+            None => {
+                // Another setter (feh, hsetroot, a previous run) left a retained pixmap behind,
+                // the convention is to kill the client that owns it once we take over.
+                // Only when both properties name it, as feh and Esetroot check, a stale one may
+                // belong to a live client.
+                let mut previous = vec!();
+                for atom in atoms.iter() {
+                    previous.push(
+                        self.conn
+                            .get_property(false, root, *atom, AtomEnum::PIXMAP, 0, 1)?
+                            .reply()?
+                            .value32()
+                            .and_then(|mut v| v.next()),
+                    );
+                }
+                if let [Some(xroot), Some(esetroot)] = previous[..] {
+                    if xroot == esetroot && esetroot != 0 {
+                        self.conn.kill_client(esetroot)?;
+                    }
+                }
+            }
+        }
+
+        for atom in atoms.iter() {
+            self.conn
+                .change_property32(PropMode::REPLACE, root, *atom, AtomEnum::PIXMAP, &[pixmap])?;
+        }
+
+        Ok(())
+    }
+}
+
+// Calls `changed` whenever RandR reports a new screen layout (a monitor plugged, unplugged,
+// moved or rotated), from a thread of its own with a connection of its own. The thread stops
+// once `changed` returns false or the X server goes away.
+pub fn watch_screen_changes<F>(mut changed: F) -> Result<()>
+where
+    F: FnMut() -> bool + Send + 'static,
+{
+    let (conn, screen_num) = x11rb::connect(None)?;
+    // The server only sends RandR 1.2+ events to clients that asked for the version.
+    conn.randr_query_version(1, 5)?.reply()?;
+    let root = conn.setup().roots[screen_num].root;
+    conn.randr_select_input(root, NotifyMask::SCREEN_CHANGE)?;
+    conn.flush()?;
+
+    spawn(move || {
+        while let Ok(event) = conn.wait_for_event() {
+            if let Event::RandrScreenChangeNotify(_) = event {
+                if !changed() {
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::gen_rand_string;
+    use image::{Rgb, RgbImage};
+    use std::fs;
+
+    fn root_pixmaps(conn: &RustConnection, root: u32) -> Vec<Option<u32>> {
+        ROOT_ATOMS
+            .iter()
+            .map(|name| {
+                let atom = conn.intern_atom(false, name).unwrap().reply().unwrap().atom;
+                conn.get_property(false, root, atom, AtomEnum::PIXMAP, 0, 1)
+                    .unwrap()
+                    .reply()
+                    .unwrap()
+                    .value32()
+                    .and_then(|mut v| v.next())
+            })
+            .collect()
+    }
+
+    fn exists(conn: &RustConnection, pixmap: u32) -> bool {
+        conn.get_geometry(pixmap).unwrap().reply().is_ok()
+    }
+
+    // What feh leaves behind: a retained pixmap of a client that is gone, named by both
+    // properties, or by _XROOTPMAP_ID only when `both` is false.
+    fn leave_pixmap(both: bool) -> u32 {
+        let (conn, screen_num) = x11rb::connect(None).unwrap();
+        conn.set_close_down_mode(CloseDown::RETAIN_PERMANENT).unwrap();
+        let screen = &conn.setup().roots[screen_num];
+        let pixmap = conn.generate_id().unwrap();
+        conn.create_pixmap(screen.root_depth, pixmap, screen.root, 1, 1).unwrap();
+        for (i, name) in ROOT_ATOMS.iter().enumerate() {
+            let atom = conn.intern_atom(false, name).unwrap().reply().unwrap().atom;
+            let value = if i == 0 || both { pixmap } else { screen.root };
+            conn.change_property32(PropMode::REPLACE, screen.root, atom, AtomEnum::PIXMAP, &[value]).unwrap();
+        }
+        conn.sync().unwrap();
+
+        pixmap
+    }
+
+    // Needs an X server: xvfb-run -s "-screen 0 640x480x24" cargo test -- --ignored root_window
+    #[test]
+    #[ignore]
+    fn root_window_replaces_the_root_pixmap() {
+        if std::env::var("DISPLAY").map(|d| d.is_empty()).unwrap_or(true) {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("wallpaper-test-{}", gen_rand_string()));
+        fs::create_dir_all(&dir).unwrap();
+        let paths = [Rgb([255, 0, 0]), Rgb([0, 0, 255])]
+            .iter()
+            .enumerate()
+            .map(|(i, color)| {
+                let path = dir.join(format!("{}.png", i)).display().to_string();
+                RgbImage::from_pixel(16, 9, *color).save(&path).unwrap();
+                path
+            })
+            .collect::<Vec<String>>();
+        let (conn, screen_num) = x11rb::connect(None).unwrap();
+        let root = conn.setup().roots[screen_num].root;
+
+        // A stale _XROOTPMAP_ID alone may belong to a live client, it is left alone.
+        let stale = leave_pixmap(false);
+        let mut window = RootWindow::connect().unwrap();
+        window.set_wallpaper(&paths[..1], ScaleMode::Fill).unwrap();
+        // Everything it sent is done before we look.
+        window.conn.sync().unwrap();
+        assert!(exists(&conn, stale));
+
+        // Named by both, the client that left it is killed along with it.
+        let left = leave_pixmap(true);
+        let mut window = RootWindow::connect().unwrap();
+        window.set_wallpaper(&paths[..1], ScaleMode::Fill).unwrap();
+        window.conn.sync().unwrap();
+        assert!(!exists(&conn, left));
+        let first = window.pixmap.unwrap();
+        assert_eq!(root_pixmaps(&conn, root), vec!(Some(first), Some(first)));
+
+        // Our own previous pixmap is freed, the new one is kept once we are gone.
+        window.set_wallpaper(&paths[1..], ScaleMode::Fill).unwrap();
+        window.conn.sync().unwrap();
+        let second = window.pixmap.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(root_pixmaps(&conn, root), vec!(Some(second), Some(second)));
+        assert!(!exists(&conn, first));
+        drop(window);
+        assert!(exists(&conn, second));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    // Needs an X server: xvfb-run -s "-screen 0 640x480x24" cargo test -- --ignored root_window
+    #[test]
+    #[ignore]
+    fn root_window_captures_and_puts_back_what_it_shows() {
+        if std::env::var("DISPLAY").map(|d| d.is_empty()).unwrap_or(true) {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("wallpaper-test-{}", gen_rand_string()));
+        fs::create_dir_all(&dir).unwrap();
+        let (red, green) = (dir.join("red.png"), dir.join("green.png"));
+        RgbImage::from_pixel(16, 9, Rgb([255, 0, 0])).save(&red).unwrap();
+        RgbImage::from_pixel(16, 9, Rgb([0, 255, 0])).save(&green).unwrap();
+        let captured = dir.join("captured.png");
+
+        let mut window = RootWindow::connect().unwrap();
+        window.set_wallpaper(&[red.display().to_string()], ScaleMode::Stretch).unwrap();
+        window.conn.sync().unwrap();
+        assert!(RootWindow::connect().unwrap().capture(&captured).unwrap());
+        let picture = image::open(&captured).unwrap().to_rgb8();
+        let screen = window.screen();
+        assert_eq!(picture.dimensions(), (screen.width_in_pixels as u32, screen.height_in_pixels as u32));
+        assert!(picture.pixels().all(|p| *p == Rgb([255, 0, 0])));
+
+        window.set_wallpaper(&[green.display().to_string()], ScaleMode::Stretch).unwrap();
+        window.set_picture(&picture).unwrap();
+        window.conn.sync().unwrap();
+        assert!(RootWindow::connect().unwrap().capture(&captured).unwrap());
+        assert_eq!(image::open(&captured).unwrap().to_rgb8(), picture);
+        let _ = fs::remove_dir_all(dir);
+    }
+}